@@ -0,0 +1,141 @@
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+
+use crate::reclaim::{GuardCount, Reclaim};
+use crate::{Arc, ArcData};
+
+// Arcを丸ごと入れ替えられる置き場所。loadはロックを取らない
+// loadはポインタを読んでからdata_ref_countを増やすまでの間、Rで自分の存在を示す
+// swapは入れ替えた後にRで古いポインタを読んだloadがいなくなるまで待ってから古いArcを返す
+pub struct AtomicArc<T, R = GuardCount> {
+  // AtomicArc自身がstrong参照を1つ持つ
+  ptr: AtomicPtr<ArcData<T>>,
+  // 読んでいる途中のloadをどう数えるか
+  reclaim: R,
+  // swapどうしを直列にする
+  writer: AtomicBool,
+  // Send/SyncはArc<T>と同じ条件にする
+  _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> AtomicArc<T> {
+  pub fn new(arc: Arc<T>) -> Self {
+    Self::with_reclaim(arc, GuardCount::default())
+  }
+}
+
+impl<T, R: Reclaim> AtomicArc<T, R> {
+  pub fn with_reclaim(arc: Arc<T>, reclaim: R) -> Self {
+    let arc = ManuallyDrop::new(arc);
+    Self {
+      ptr: AtomicPtr::new(arc.ptr.as_ptr()),
+      reclaim,
+      writer: AtomicBool::new(false),
+      _marker: PhantomData,
+    }
+  }
+
+  pub fn load(&self) -> Arc<T> {
+    let token = self.reclaim.enter();
+    // leaveするまで、このポインタのArcはswapから返されない(=解放されない)
+    let p = unsafe { NonNull::new_unchecked(self.ptr.load(SeqCst)) };
+    let arc = Arc::clone(&ManuallyDrop::new(Arc { ptr: p }));
+    self.reclaim.leave(token);
+    arc
+  }
+
+  pub fn store(&self, arc: Arc<T>) {
+    drop(self.swap(arc));
+  }
+
+  // 古いArcを返す。そのArcを読んだloadがcloneし終えるまで待つ
+  pub fn swap(&self, arc: Arc<T>) -> Arc<T> {
+    while self.writer.compare_exchange_weak(false, true, Acquire, Relaxed).is_err() {
+      std::hint::spin_loop();
+    }
+    let new = ManuallyDrop::new(arc);
+    let old = self.ptr.swap(new.ptr.as_ptr(), SeqCst);
+    self.reclaim.synchronize();
+    self.writer.store(false, Release);
+    Arc { ptr: unsafe { NonNull::new_unchecked(old) } }
+  }
+}
+
+impl<T, R> Drop for AtomicArc<T, R> {
+  fn drop(&mut self) {
+    let p = unsafe { NonNull::new_unchecked(*self.ptr.get_mut()) };
+    drop(Arc { ptr: p });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering::Relaxed;
+  use std::thread;
+
+  use super::*;
+  use crate::reclaim::Epoch;
+
+  fn readers_while_swapping<R: Reclaim + Sync>(reclaim: R) {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+    // 版番号を2か所に持たせ、解放後に読むと食い違いやすくする
+    struct Config(u64, Box<u64>);
+    impl Drop for Config {
+      fn drop(&mut self) {
+        NUM_DROPS.fetch_add(1, Relaxed);
+      }
+    }
+
+    const SWAPS: u64 = if cfg!(miri) { 50 } else { 2000 };
+    NUM_DROPS.store(0, Relaxed);
+    let shared = AtomicArc::with_reclaim(Arc::new(Config(0, Box::new(0))), reclaim);
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+      for _ in 0..4 {
+        s.spawn(|| {
+          let mut last = 0;
+          while !done.load(Relaxed) {
+            let c = shared.load();
+            assert_eq!(c.0, *c.1);
+            // 版は戻らない
+            assert!(c.0 >= last);
+            last = c.0;
+          }
+        });
+      }
+      for v in 1..=SWAPS {
+        let old = shared.swap(Arc::new(Config(v, Box::new(v))));
+        assert_eq!(old.0, v - 1);
+      }
+      done.store(true, Relaxed);
+    });
+    // 古い版はすべて解放されている
+    assert_eq!(NUM_DROPS.load(Relaxed), SWAPS as usize);
+    let last = shared.load();
+    assert_eq!(last.0, SWAPS);
+    assert_eq!(Arc::counts(&last), (2, 1));
+    drop(shared);
+    assert_eq!(Arc::counts(&last), (1, 1));
+  }
+
+  // NUM_DROPSを共有するので、2つの方式は1つのテストの中で順に回す
+  #[test]
+  fn readers_while_swapping_each_reclaim() {
+    readers_while_swapping(GuardCount::default());
+    readers_while_swapping(Epoch::default());
+  }
+
+  #[test]
+  fn store_replaces_value() {
+    let shared = AtomicArc::new(Arc::new(String::from("a")));
+    let a = shared.load();
+    shared.store(Arc::new(String::from("b")));
+    assert_eq!(*a, "a");
+    assert_eq!(*shared.load(), "b");
+    assert_eq!(Arc::counts(&a), (1, 1));
+  }
+}
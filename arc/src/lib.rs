@@ -1,54 +1,209 @@
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
 use std::cell::UnsafeCell;
-use std::mem::ManuallyDrop;
+use std::fmt;
+use std::mem::{ManuallyDrop, MaybeUninit};
 use std::sync::atomic::fence;
-use std::usize;
 use std::{ops::Deref, ptr::NonNull, sync::atomic::AtomicUsize};
-use std::sync::atomic::Ordering::{Relaxed, Release, Acquire};
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+#[cfg(feature = "upgrade-stats")]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::{Relaxed, Release, Acquire, SeqCst};
 
-struct ArcData<T> {
+use retry_policy::{RetryPolicy, Spin};
+
+mod atomic_arc;
+mod once_arc;
+mod reclaim;
+mod shared_counter;
+#[cfg(feature = "stress")]
+mod stress;
+mod tree;
+
+pub use atomic_arc::AtomicArc;
+pub use once_arc::OnceArc;
+pub use reclaim::{Epoch, GuardCount, Reclaim};
+pub use shared_counter::SharedCounter;
+#[cfg(feature = "stress")]
+pub use stress::stress_arc;
+pub use tree::TreeNode;
+
+// from_box、slice_layout、assume_initは、ArcData<T>とArcData<[u8; 0]>やArcData<[T; 0]>の
+// ヘッダが同じ並びになることに頼っている。デフォルトのreprではそれが保証されないのでCにする
+#[repr(C)]
+struct ArcData<T: ?Sized> {
   // Arc
   data_ref_count: AtomicUsize,
   // weakの数。arcが１つでもあれば+1
   alloc_ref_count: AtomicUsize,
+  // trueならWeak::upgradeで新しいArcを作らせない
+  sealed: AtomicBool,
+  // Weak::upgradeが成功した回数と失敗した回数
+  #[cfg(feature = "upgrade-stats")]
+  upgrade_stats: (AtomicU64, AtomicU64),
+  // on_last_dropで登録したコールバック。strongが0になったときに取り出して呼ぶ
+  on_last_drop: AtomicPtr<Box<dyn FnOnce() + Send>>,
   // weakしか残ってなければdropされる
   data: UnsafeCell<ManuallyDrop<T>>,
 }
 
-pub struct Weak<T> {
+impl<T: ?Sized> ArcData<T> {
+  // strongが0になった直後、中身に触る前に呼ぶ。登録されていなければ何もしない
+  fn run_last_drop(&self) {
+    let f = self.on_last_drop.swap(std::ptr::null_mut(), Acquire);
+    if !f.is_null() {
+      unsafe { Box::from_raw(f)() };
+    }
+  }
+}
+
+impl<T> ArcData<T> {
+  // dataフィールドの先頭からのオフセット
+  const DATA_OFFSET: usize = std::mem::offset_of!(ArcData<T>, data);
+
+  // from_raw用。dataを指すポインタからArcDataの先頭に戻す
+  fn from_data_ptr(data: *const T) -> *const ArcData<T> {
+    data.wrapping_byte_sub(Self::DATA_OFFSET) as *const ArcData<T>
+  }
+}
+
+pub struct Weak<T: ?Sized> {
   ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Send + Sync> Send for Weak<T> {}
-unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Weak<T> {}
 
 impl<T> Weak<T> {
+  // Arcを持たない空のWeak。data_ref_countが0なのでupgradeは常にNoneになる
+  // dataは初期化しないまま、ヘッダだけを書いたArcDataを確保する
+  pub fn new() -> Weak<T> {
+    let p = Box::into_raw(Box::<ArcData<T>>::new_uninit()) as *mut ArcData<T>;
+    unsafe {
+      std::ptr::addr_of_mut!((*p).data_ref_count).write(AtomicUsize::new(0));
+      std::ptr::addr_of_mut!((*p).alloc_ref_count).write(AtomicUsize::new(1));
+      std::ptr::addr_of_mut!((*p).sealed).write(AtomicBool::new(false));
+      #[cfg(feature = "upgrade-stats")]
+      std::ptr::addr_of_mut!((*p).upgrade_stats).write((AtomicU64::new(0), AtomicU64::new(0)));
+      std::ptr::addr_of_mut!((*p).on_last_drop).write(AtomicPtr::new(std::ptr::null_mut()));
+      Weak { ptr: NonNull::new_unchecked(p) }
+    }
+  }
+
+  /// Weakを生ポインタにする。alloc_ref_countの分の所有権はポインタに移る
+  ///
+  /// 中身が既にdropされていれば、返ったポインタを参照外ししてはいけない
+  pub fn into_raw(weak: Self) -> *const T {
+    let weak = ManuallyDrop::new(weak);
+    // 中身がdropされているかもしれないので、参照を作らずにアドレスだけ計算する
+    unsafe { std::ptr::addr_of!((*weak.ptr.as_ptr()).data) as *const T }
+  }
+
+  /// # Safety
+  ///
+  /// ptrはWeak::into_rawが返したものでなければならず、from_rawに渡せるのは1回だけ
+  pub unsafe fn from_raw(ptr: *const T) -> Self {
+    let header = ArcData::from_data_ptr(ptr) as *mut ArcData<T>;
+    Weak { ptr: unsafe { NonNull::new_unchecked(header) } }
+  }
+}
 
+impl<T: ?Sized> Weak<T> {
   fn data(&self)-> &ArcData<T> {
     unsafe{ self.ptr.as_ref()}
   }
 
   pub fn upgrade(&self) -> Option<Arc<T>> {
+    let arc = self.upgrade_inner();
+    #[cfg(feature = "upgrade-stats")]
+    {
+      let (ok, failed) = &self.data().upgrade_stats;
+      if arc.is_some() { ok } else { failed }.fetch_add(1, Relaxed);
+    }
+    arc
+  }
+
+  // upgradeせずに数を見る。中身がdropされた後もアロケーションは残っているので読める
+  pub fn strong_count(&self) -> usize {
+    self.data().data_ref_count.load(Relaxed)
+  }
+
+  // 自分を含むWeakの数。strongが残っている間はその暗黙のweakを引く
+  // Weakがある間はget_mutのロック(usize::MAX)はかからない
+  pub fn weak_count(&self) -> usize {
+    let n = self.data().alloc_ref_count.load(Relaxed);
+    if self.strong_count() > 0 { n - 1 } else { n }
+  }
+
+  // (成功した回数, Noneだった回数)。同じアロケーションを指すWeak全体での合計
+  #[cfg(feature = "upgrade-stats")]
+  pub fn upgrade_stats(&self) -> (u64, u64) {
+    let (ok, failed) = &self.data().upgrade_stats;
+    (ok.load(Relaxed), failed.load(Relaxed))
+  }
+
+  // 順にupgradeし、生きているものと、upgradeできなかったWeakの位置(0始まり)を返す
+  // 1つずつupgradeするので、全体として同じ瞬間の状態ではない
+  pub fn upgrade_all<'a>(weaks: impl Iterator<Item = &'a Weak<T>>) -> (Vec<Arc<T>>, Vec<usize>)
+  where
+    T: 'a,
+  {
+    let mut live = Vec::new();
+    let mut dead = Vec::new();
+    for (i, w) in weaks.enumerate() {
+      match w.upgrade() {
+        Some(a) => live.push(a),
+        None => dead.push(i),
+      }
+    }
+    (live, dead)
+  }
+
+  // 同じアロケーションを指しているか。中身がdropされた後でも比べられる
+  pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+    std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+  }
+
+  fn upgrade_inner(&self) -> Option<Arc<T>> {
     let mut count = self.data().data_ref_count.load(Relaxed);
     loop {
-      if count == 0 {
+      if count == 0 || self.data().sealed.load(Relaxed) {
         return None;
       }
       assert!(count < usize::MAX);
       if let Err(e) = self.data().data_ref_count.compare_exchange_weak(
         count,
         count + 1,
-        Relaxed,
+        SeqCst,
         Relaxed,
       ) {
         count = e;
         continue;
       }
-      return Some(Arc { ptr: self.ptr });
+      let arc = Arc { ptr: self.ptr };
+      // 増やした後にsealされていたら取り消す。seal後に増えたArcは外に出さない
+      if self.data().sealed.load(SeqCst) {
+        drop(arc);
+        return None;
+      }
+      return Some(arc);
     }
   }
 }
 
-impl<T> Clone for Weak<T> {
+// 中身はもうdropされているかもしれないので触らない
+impl<T: ?Sized> fmt::Debug for Weak<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "(Weak)")
+  }
+}
+
+impl<T> Default for Weak<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
   fn clone(&self) -> Self {
     if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2{
       std::process::abort();
@@ -58,23 +213,28 @@ impl<T> Clone for Weak<T> {
   }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
   fn drop(&mut self) {
     if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
       fence(Acquire);
       // 最後の参照がドロップされたとき、メモリを解放する
-      drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
+      // dataはdrop済みか、Weak::newなら初期化されていないので、dropせずにメモリだけ返す
+      // Arc<[T]>もここに来るので、大きさはポインタのメタデータから求める
+      unsafe {
+        let layout = Layout::for_value(self.ptr.as_ref());
+        dealloc(self.ptr.as_ptr() as *mut u8, layout);
+      }
     }
   }
 }
 
 
-pub struct Arc<T> {
+pub struct Arc<T: ?Sized> {
   ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Send + Sync> Send for Arc<T> {}
-unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Arc<T> {}
 
 impl<T> Arc<T> {
   pub fn new(data: T) -> Arc<T> {
@@ -84,41 +244,245 @@ impl<T> Arc<T> {
           Box::leak(Box::new(ArcData {
           data_ref_count: AtomicUsize::new(1),
           alloc_ref_count: AtomicUsize::new(1),
+          sealed: AtomicBool::new(false),
+          #[cfg(feature = "upgrade-stats")]
+          upgrade_stats: (AtomicU64::new(0), AtomicU64::new(0)),
+          on_last_drop: AtomicPtr::new(std::ptr::null_mut()),
           data: UnsafeCell::new(ManuallyDrop::new(data)),
       }))),
     }
   }
 
+
+  // 作っている途中の自分を指すWeakをfに渡す。fの中ではdata_ref_countが0なのでupgradeはNoneになる
+  // fがpanicしたらWeakのdropで、書きかけのArcDataは中身に触れずに解放される
+  pub fn new_cyclic<F>(f: F) -> Arc<T>
+  where
+    F: FnOnce(&Weak<T>) -> T,
+  {
+    let weak = Weak::new();
+    let data = f(&weak);
+    unsafe { weak.data().data.get().write(ManuallyDrop::new(data)) };
+    // weakの持っていたalloc_ref_countの1は、strongが持つ暗黙のweakになる
+    let weak = ManuallyDrop::new(weak);
+    // Releaseで、upgradeしたスレッドに書き込んだdataが見える
+    weak.data().data_ref_count.store(1, Release);
+    Arc { ptr: weak.ptr }
+  }
+
+  // 共有されていなければそのまま、されていれば中身をcloneした新しいアロケーションに付け替えてから&mut Tを返す
+  // strongが1つでもWeakがあればcloneする。そうしないとupgradeしたWeakに書き換えが見えてしまう
+  pub fn make_mut(arc: &mut Self) -> &mut T
+  where
+    T: Clone,
+  {
+    if !Self::is_unique(arc) {
+      *arc = Arc::new(T::clone(arc));
+    }
+    // &mut selfで唯一のArcを持っているので、is_uniqueの後に他の参照が増えることはない
+    fence(Acquire);
+    unsafe { &mut *arc.data().data.get() }
+  }
+
+  // 最後のArcなら中身を取り出す。1->0のCASなので、先にupgradeで増やされていればErrになり、
+  // 0にした後はupgradeが失敗する
+  pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+    if arc.data().data_ref_count.compare_exchange(1, 0, Acquire, Relaxed).is_err() {
+      return Err(arc);
+    }
+    arc.data().run_last_drop();
+    let arc = ManuallyDrop::new(arc);
+    // Tのdropは走らせずに中身をムーブする
+    let value = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+    // 暗黙のweakのドロップ
+    drop(Weak { ptr: arc.ptr });
+    Ok(value)
+  }
+
+  // 最後のArcなら中身をそのまま、そうでなければcloneを返す
+  pub fn unwrap_or_clone(arc: Self) -> T
+  where
+    T: Clone,
+  {
+    Self::try_unwrap(arc).unwrap_or_else(|arc| (*arc).clone())
+  }
+
+  // Dropと同じく数を減らし、最後の1つだったときだけ中身をdropせずに返す
+  // 複数のスレッドが同時に呼んでも、どれか1つは必ずSomeになる(try_unwrapではどれも失敗しうる)
+  pub fn into_inner(arc: Self) -> Option<T> {
+    let arc = ManuallyDrop::new(arc);
+    if arc.data().data_ref_count.fetch_sub(1, Release) != 1 {
+      return None;
+    }
+    fence(Acquire);
+    arc.data().run_last_drop();
+    let value = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+    // 暗黙のweakのドロップ。他のWeakが残っていればArcDataはまだ解放されない
+    drop(Weak { ptr: arc.ptr });
+    Some(value)
+  }
+
+  /// Arcを生ポインタにする。カウントは変えず、data_ref_countの1つ分の所有権がポインタに移る
+  ///
+  /// 返すのはヘッダではなくdataフィールドを指すポインタなので、そのまま&Tとして使える
+  pub fn into_raw(arc: Self) -> *const T {
+    let arc = ManuallyDrop::new(arc);
+    Self::as_ptr(&arc)
+  }
+
+  /// into_rawの逆。dataのポインタからDATA_OFFSET(offset_of!で求めたdataフィールドの位置)だけ
+  /// 戻ってArcDataの先頭を求める
+  ///
+  /// # Safety
+  ///
+  /// ptrはArc::into_rawが返したものでなければならず、from_rawに渡せるのは1回だけ
+  pub unsafe fn from_raw(ptr: *const T) -> Self {
+    let header = ArcData::from_data_ptr(ptr) as *mut ArcData<T>;
+    Arc { ptr: unsafe { NonNull::new_unchecked(header) } }
+  }
+
+  /// into_rawしたポインタのdata_ref_countを1増やす。増やした分はもう一度from_rawして返す
+  ///
+  /// # Safety
+  ///
+  /// ptrはArc::into_rawが返したもので、そのArcの分の所有権がまだ残っていなければならない
+  pub unsafe fn increment_strong_count(ptr: *const T) {
+    // 自分の分はdropしないまま借りてcloneする。Cloneと同じくRelaxedで増やす
+    let arc = ManuallyDrop::new(unsafe { Self::from_raw(ptr) });
+    std::mem::forget(Arc::clone(&arc));
+  }
+
+  /// into_rawしたポインタのdata_ref_countを1減らす。0になればDropと同じく中身と暗黙のweakをdropする
+  ///
+  /// # Safety
+  ///
+  /// ptrはArc::into_rawが返したもので、減らす1つ分の所有権を呼び出し側が持っていなければならない
+  pub unsafe fn decrement_strong_count(ptr: *const T) {
+    drop(unsafe { Self::from_raw(ptr) });
+  }
+}
+
+impl<T> Arc<T> {
+  /// 中身を初期化しないまま確保する。カウントはnewと同じく両方1
+  ///
+  /// get_mutで書き込んでからassume_initでArc<T>にする
+  pub fn new_uninit() -> Arc<MaybeUninit<T>> {
+    Arc::new(MaybeUninit::uninit())
+  }
+}
+
+impl<T> Arc<MaybeUninit<T>> {
+  /// ポインタの型を付け替えるだけ。MaybeUninit<T>はTと同じサイズ・アラインなので、ArcDataの並びも変わらない
+  ///
+  /// # Safety
+  ///
+  /// 中身は初期化済みでなければならない。他のArcやWeakが残っていれば、それらはMaybeUninit<T>のまま同じ値を指す
+  pub unsafe fn assume_init(arc: Self) -> Arc<T> {
+    debug_assert_eq!(ArcData::<MaybeUninit<T>>::DATA_OFFSET, ArcData::<T>::DATA_OFFSET);
+    let arc = ManuallyDrop::new(arc);
+    Arc { ptr: arc.ptr.cast() }
+  }
+}
+
+impl<T: ?Sized> Arc<T> {
   fn data(&self) -> &ArcData<T> {
     unsafe { self.ptr.as_ref() }
   }
 
+  // Boxの中身をArcDataにムーブする。Box<dyn Trait>からArc<dyn Trait>を作れる
+  // 中身はバイト列としてコピーし、元のBoxはdropせずにメモリだけ解放する
+  pub fn from_box(b: Box<T>) -> Arc<T> {
+    let value_layout = Layout::for_value::<T>(&b);
+    let raw = Box::into_raw(b);
+    // repr(C)なのでdataより前のフィールドの並びはTによらない。dataの位置はそこからTのアラインに切り上げたところ
+    let header = Layout::from_size_align(
+      std::mem::offset_of!(ArcData<[u8; 0]>, data),
+      std::mem::align_of::<ArcData<[u8; 0]>>(),
+    ).unwrap();
+    let (layout, offset) = header.extend(value_layout).expect("Arc::from_box: value too large");
+    let layout = layout.pad_to_align();
+
+    let mem = unsafe { alloc(layout) };
+    if mem.is_null() {
+      handle_alloc_error(layout);
+    }
+    // rawのメタデータ(長さやvtable)はそのまま使い、アドレスだけ新しいアロケーションに差し替える
+    let p = raw.with_addr(mem.addr()) as *mut ArcData<T>;
+    unsafe {
+      std::ptr::addr_of_mut!((*p).data_ref_count).write(AtomicUsize::new(1));
+      std::ptr::addr_of_mut!((*p).alloc_ref_count).write(AtomicUsize::new(1));
+      std::ptr::addr_of_mut!((*p).sealed).write(AtomicBool::new(false));
+      #[cfg(feature = "upgrade-stats")]
+      std::ptr::addr_of_mut!((*p).upgrade_stats).write((AtomicU64::new(0), AtomicU64::new(0)));
+      std::ptr::addr_of_mut!((*p).on_last_drop).write(AtomicPtr::new(std::ptr::null_mut()));
+      debug_assert_eq!(std::ptr::addr_of_mut!((*p).data) as *mut u8, mem.add(offset));
+      std::ptr::copy_nonoverlapping(raw as *const u8, mem.add(offset), value_layout.size());
+      // サイズ0のBoxはアロケーションを持たない
+      if value_layout.size() != 0 {
+        dealloc(raw as *mut u8, value_layout);
+      }
+      Arc { ptr: NonNull::new_unchecked(p) }
+    }
+  }
+
+  // dataを指すポインタ。カウントは変えないので、arcが生きている間だけ使える
+  pub fn as_ptr(arc: &Self) -> *const T {
+    arc.data().data.get() as *const T
+  }
+
   pub fn get_mut(arc: &mut Self)-> Option<&mut T> {
+    // alloc_ref_countをusize::MAXにしている間はdowngradeが待つので、Weakから新しいArcは作られない
+    // (Weakがあればそもそも1ではない)。他のArcは無いので、cloneで増えることもない
     if arc.data().
     alloc_ref_count.
     compare_exchange(1,usize::MAX, Acquire, Relaxed).is_err() {
       return None;
     }
 
-    let is_unique = arc.data().data_ref_count.load(Relaxed) == 1;
+    // ロックを持ったまま調べる。Acquireで、他のArcがdropされる前にした読み書きが全て見える
+    let is_unique = arc.data().data_ref_count.load(Acquire) == 1;
+    // 結論を出してからロックを外す
     arc.data().alloc_ref_count.store(1, Release);
     if !is_unique {
       return None;
     }
 
-    fence(Acquire);
     unsafe { Some(&mut *arc.data().data.get()) }
   }
 
+  // get_mutが成功するかどうかを&selfで調べる。alloc_ref_countは必ず1に戻す
+  // &Arcが他のスレッドと共有されていれば、戻った直後にcloneされているかもしれない
+  pub fn is_unique(arc: &Self) -> bool {
+    if arc.data().
+    alloc_ref_count.
+    compare_exchange(1, usize::MAX, Acquire, Relaxed).is_err() {
+      return false;
+    }
+    let is_unique = arc.data().data_ref_count.load(Relaxed) == 1;
+    arc.data().alloc_ref_count.store(1, Release);
+    is_unique
+  }
+
   pub fn downgrade(arc: &Self) -> Weak<T> {
+    Self::downgrade_with(arc, &Spin)
+  }
+
+  // get_mutがalloc_ref_countをusize::MAXにしてロックしている間、policyで待つ
+  pub fn downgrade_with(arc: &Self, policy: &impl RetryPolicy) -> Weak<T> {
+    let mut attempt = 0;
     let mut n = arc.data().alloc_ref_count.load(Relaxed);
     loop {
       if n == usize::MAX {
-        std::hint::spin_loop();
+        policy.backoff(attempt);
+        attempt = attempt.saturating_add(1);
         n = arc.data().alloc_ref_count.load(Relaxed);
         continue;
       }
-      assert!(n < usize::MAX - 1);
+      // Weak::cloneと同じ上限。usize::MAXのロック状態とは離れているので、本物の数と取り違えない
+      // assertでunwindさせず、他のカウンタと同じくabortする
+      if n > usize::MAX / 2 {
+        std::process::abort();
+      }
       if let Err(e) = arc.data().alloc_ref_count.compare_exchange_weak(
         n,
         n + 1,
@@ -128,12 +492,225 @@ impl<T> Arc<T> {
         n = e;
         continue;
       }
+      policy.acquired(attempt);
       return Weak { ptr: arc.ptr };
     }
   }
+
+  // 中身ではなく、同じアロケーションを指しているかで比べる。TがPartialEqでなくてもよい
+  pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+    std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+  }
+
+  // 最後のstrongがdropされたとき(try_unwrap/into_innerで取り出されたときも)、中身に触る前にfを呼ぶ
+  // 登録できるのは1つだけ。既に登録されていればErr
+  pub fn on_last_drop(arc: &Self, f: impl FnOnce() + Send + 'static) -> Result<(), LastDropAlreadySet> {
+    let f: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+    let p = Box::into_raw(f);
+    // 自分がstrongを持っているので、この間に呼ばれることはない
+    if arc.data().on_last_drop.compare_exchange(std::ptr::null_mut(), p, Release, Relaxed).is_err() {
+      drop(unsafe { Box::from_raw(p) });
+      return Err(LastDropAlreadySet);
+    }
+    Ok(())
+  }
+
+  // これ以降Weak::upgradeは常にNoneを返す。既存のArcはそのまま使えるので、
+  // 新しい参照を増やさずに今ある参照がなくなるのを待てる
+  pub fn seal(arc: &Self) {
+    arc.data().sealed.store(true, SeqCst);
+  }
+
+  pub fn is_sealed(arc: &Self) -> bool {
+    arc.data().sealed.load(Relaxed)
+  }
+
+  // (data_ref_count, alloc_ref_count)の生の値。別々にloadするので並行実行中は目安
+  // alloc_ref_countはstrongが残っている間は暗黙のweakの分だけ多い
+  pub fn counts(arc: &Self) -> (usize, usize) {
+    let data = arc.data();
+    (data.data_ref_count.load(Relaxed), data.alloc_ref_count.load(Relaxed))
+  }
+
+  // 今のArcの数。他のスレッドが同時にclone/dropしていれば、返した時点で古い値かもしれない
+  pub fn strong_count(arc: &Self) -> usize {
+    arc.data().data_ref_count.load(Relaxed)
+  }
+
+  // 今のWeakの数。strong_countと同じく目安で、strongが残っている間の暗黙のweakは数えない
+  pub fn weak_count(arc: &Self) -> usize {
+    let n = arc.data().alloc_ref_count.load(Relaxed);
+    // get_mut/is_uniqueがロックしている間はusize::MAXになっている。そのときWeakは0個
+    if n == usize::MAX {
+      return 0;
+    }
+    n - 1
+  }
+
+  // ArcDataの中身を説明する文字列。テストや例での観察用
+  pub fn debug_layout(arc: &Self) -> String {
+    let (strong, alloc) = Self::counts(arc);
+    format!(
+      "ArcData<{}> @ {:p} {{ data_ref_count: {}, alloc_ref_count: {}, size: {} }}",
+      std::any::type_name::<T>(),
+      arc.ptr.as_ptr(),
+      strong,
+      alloc,
+      std::mem::size_of_val(arc.data()),
+    )
+  }
+
+  /// 境界値テスト用にdata_ref_countを直接書き換える
+  ///
+  /// # Safety
+  ///
+  /// 実際のArcの数と食い違ったままdropすると、二重解放やリークになる。
+  /// テストで元の値に戻すことが前提
+  #[cfg(any(test, feature = "test-hooks"))]
+  #[doc(hidden)]
+  pub unsafe fn __set_strong_count(arc: &Self, v: usize) {
+    arc.data().data_ref_count.store(v, Release);
+  }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Debug::fmt(&**self, f)
+  }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&**self, f)
+  }
+}
+
+// 同じアロケーションなら中身を見ずにtrueにする
+// そのため、NaNを持つArc<f64>は自分自身とは等しくなる(std::sync::ArcはT: Eqのときだけこうする)
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(self, other) || **self == **other
+  }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for Arc<T> {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    (**self).partial_cmp(&**other)
+  }
+}
+
+impl<T: ?Sized + Ord> Ord for Arc<T> {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (**self).cmp(&**other)
+  }
+}
+
+impl<T: ?Sized + std::hash::Hash> std::hash::Hash for Arc<T> {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    (**self).hash(state)
+  }
+}
+
+// Hash/Eqは中身に合わせてあるので、HashMap<Arc<T>, V>を&Tで引ける
+impl<T: ?Sized> std::borrow::Borrow<T> for Arc<T> {
+  fn borrow(&self) -> &T {
+    self
+  }
+}
+
+impl<T: ?Sized> AsRef<T> for Arc<T> {
+  fn as_ref(&self) -> &T {
+    self
+  }
+}
+
+impl<T: Default> Default for Arc<T> {
+  fn default() -> Self {
+    Arc::new(T::default())
+  }
+}
+
+impl<T> From<T> for Arc<T> {
+  fn from(data: T) -> Self {
+    Arc::new(data)
+  }
+}
+
+// on_last_dropで既にコールバックが登録されている
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastDropAlreadySet;
+
+// 確保に失敗した。try_from_iter_exactが返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl<T: Clone> Arc<[T]> {
+  // cloneがpanicすると、確保したメモリとclone済みの要素はリークする
+  pub fn from_slice(slice: &[T]) -> Arc<[T]> {
+    Self::from_iter_exact(slice.iter().cloned())
+  }
+}
+
+impl<T> Arc<[T]> {
+  // 長さの分だけ1回で確保し、要素を直接ムーブする。途中でVecは作らない
+  // 確保に失敗したらhandle_alloc_errorで止まる
+  pub fn from_iter_exact<I: ExactSizeIterator<Item = T>>(iter: I) -> Arc<[T]> {
+    let n = iter.len();
+    match Self::try_from_iter_exact(iter) {
+      Ok(arc) => arc,
+      Err(AllocError) => match Self::slice_layout(n) {
+        Some(layout) => handle_alloc_error(layout),
+        None => panic!("Arc::from_iter_exact: too many items"),
+      },
+    }
+  }
+
+  // from_iter_exactと同じだが、確保に失敗したらErrを返す
+  // lenより多く返す分は読まない。少なければ書いた要素をdropし、メモリを解放してからpanicする
+  // nextがpanicすると、確保したメモリと書いた要素はリークする
+  pub fn try_from_iter_exact<I: ExactSizeIterator<Item = T>>(iter: I) -> Result<Arc<[T]>, AllocError> {
+    let n = iter.len();
+    let layout = Self::slice_layout(n).ok_or(AllocError)?;
+    let mem = unsafe { alloc(layout) };
+    if mem.is_null() {
+      return Err(AllocError);
+    }
+    let p = std::ptr::slice_from_raw_parts_mut(mem as *mut T, n) as *mut ArcData<[T]>;
+    unsafe {
+      let elems = std::ptr::addr_of_mut!((*p).data) as *mut T;
+      let mut written = 0;
+      for x in iter.take(n) {
+        elems.add(written).write(x);
+        written += 1;
+      }
+      if written != n {
+        std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(elems, written));
+        dealloc(mem, layout);
+        panic!("Arc::try_from_iter_exact: iterator returned fewer items than its len");
+      }
+      std::ptr::addr_of_mut!((*p).data_ref_count).write(AtomicUsize::new(1));
+      std::ptr::addr_of_mut!((*p).alloc_ref_count).write(AtomicUsize::new(1));
+      std::ptr::addr_of_mut!((*p).sealed).write(AtomicBool::new(false));
+      #[cfg(feature = "upgrade-stats")]
+      std::ptr::addr_of_mut!((*p).upgrade_stats).write((AtomicU64::new(0), AtomicU64::new(0)));
+      std::ptr::addr_of_mut!((*p).on_last_drop).write(AtomicPtr::new(std::ptr::null_mut()));
+      Ok(Arc { ptr: NonNull::new_unchecked(p) })
+    }
+  }
+
+  // ヘッダと要素を1つのアロケーションに並べる。長さはポインタのメタデータが持つ
+  // repr(C)なので、ArcData<[T]>は同じ並びのArcData<[T; 0]>の後ろに要素が続く形になる
+  fn slice_layout(n: usize) -> Option<Layout> {
+    let offset = std::mem::offset_of!(ArcData<[T; 0]>, data);
+    let align = std::mem::align_of::<ArcData<[T; 0]>>();
+    let size = Layout::array::<T>(n).ok().and_then(|a| offset.checked_add(a.size()))?;
+    Some(Layout::from_size_align(size, align).ok()?.pad_to_align())
+  }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized> Deref for Arc<T> {
   type Target = T;
 
   fn deref(&self) -> &Self::Target {
@@ -141,7 +718,7 @@ impl<T> Deref for Arc<T> {
   }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized> Clone for Arc<T> {
   fn clone(&self) -> Arc<T> {
     if self.data().data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2{
       std::process::abort();
@@ -151,11 +728,12 @@ impl<T> Clone for Arc<T> {
   }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
   fn drop(&mut self) {
     // fetch_subでloadを行ってるからfenceで先行発生関係ができる
     if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
       fence(Acquire);
+      self.data().run_last_drop();
       // 最後の参照がドロップされたとき、メモリを解放する
       unsafe {ManuallyDrop::drop(&mut *self.data().data.get())};
       // 暗黙のweakのドロップ
@@ -207,4 +785,674 @@ mod tests {
       assert!(z.upgrade().is_none());
     }
 
+    #[test]
+    fn seal_blocks_upgrade() {
+      let a = Arc::new(String::from("resource"));
+      let b = a.clone();
+      let w = Arc::downgrade(&a);
+      assert!(w.upgrade().is_some());
+
+      Arc::seal(&a);
+      assert!(Arc::is_sealed(&b));
+      assert!(w.upgrade().is_none());
+      // 既存のArcはそのまま使える
+      assert_eq!(*b, "resource");
+      assert_eq!(Arc::counts(&a).0, 2);
+
+      drop(a);
+      drop(b);
+      assert!(w.upgrade().is_none());
+    }
+
+    #[cfg(feature = "upgrade-stats")]
+    #[test]
+    fn upgrade_stats() {
+      let a = Arc::new(0);
+      let w = Arc::downgrade(&a);
+      let w2 = w.clone();
+      assert!(w.upgrade().is_some());
+      assert!(w2.upgrade().is_some());
+      drop(a);
+      assert!(w.upgrade().is_none());
+      assert!(w.upgrade().is_none());
+      assert!(w2.upgrade().is_none());
+      assert_eq!(w.upgrade_stats(), (2, 3));
+    }
+
+    #[test]
+    fn raw_counts() {
+      let a = Arc::new(1u32);
+      assert_eq!(Arc::counts(&a), (1, 1));
+      let b = a.clone();
+      let w1 = Arc::downgrade(&a);
+      let w2 = w1.clone();
+      // weak 2つ + strongがある間の暗黙のweak
+      assert_eq!(Arc::counts(&a), (2, 3));
+      assert!(Arc::debug_layout(&a).contains("data_ref_count: 2, alloc_ref_count: 3"));
+
+      drop(w1);
+      drop(b);
+      assert_eq!(Arc::counts(&a), (1, 2));
+      drop(w2);
+      assert_eq!(Arc::counts(&a), (1, 1));
+    }
+
+    #[test]
+    fn strong_and_weak_count() {
+      let a = Arc::new(1u32);
+      assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (1, 0));
+      let b = a.clone();
+      let c = b.clone();
+      let w1 = Arc::downgrade(&a);
+      let w2 = Arc::downgrade(&c);
+      assert_eq!((Arc::strong_count(&a), Arc::weak_count(&b)), (3, 2));
+
+      drop(w1);
+      drop(c);
+      assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (2, 1));
+      drop(w2);
+      drop(b);
+      assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (1, 0));
+    }
+
+    #[test]
+    fn make_mut_clones_only_when_shared() {
+      let mut a = Arc::new(vec![1]);
+      let p = a.ptr;
+      Arc::make_mut(&mut a).push(2);
+      // 共有されていなければその場で書き換える
+      assert_eq!(a.ptr, p);
+
+      let b = a.clone();
+      Arc::make_mut(&mut a).push(3);
+      assert_ne!(a.ptr, p);
+      assert_eq!(b.ptr, p);
+      assert_eq!(*a, [1, 2, 3]);
+      assert_eq!(*b, [1, 2]);
+      assert_eq!(Arc::counts(&b), (1, 1));
+
+      // strongが1つでもWeakがあればcloneし、Weakからは書き換えが見えない
+      let w = Arc::downgrade(&b);
+      let mut b = b;
+      Arc::make_mut(&mut b).push(4);
+      assert_ne!(b.ptr, p);
+      assert!(w.upgrade().is_none());
+      assert_eq!(*b, [1, 2, 4]);
+
+      let p = b.ptr;
+      Arc::make_mut(&mut b).push(5);
+      assert_eq!(b.ptr, p);
+    }
+
+    #[test]
+    fn get_mut_never_aliased() {
+      for _ in 0..50 {
+        let mut a = Arc::new(0u64);
+        let done = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+          for _ in 0..4 {
+            let b = a.clone();
+            let done = &done;
+            s.spawn(move || {
+              for _ in 0..100 {
+                let c = b.clone();
+                let w = Arc::downgrade(&c);
+                assert_eq!(*w.upgrade().unwrap(), *c);
+              }
+              // bを手放す前に数える。get_mutが成功したなら、全員がここを通った後のはず
+              done.fetch_add(1, Relaxed);
+              drop(b);
+            });
+          }
+          loop {
+            if let Some(v) = Arc::get_mut(&mut a) {
+              assert_eq!(done.load(Relaxed), 4, "get_mut succeeded while shared");
+              *v += 1;
+              break;
+            }
+            std::hint::spin_loop();
+          }
+        });
+        assert_eq!(Arc::counts(&a), (1, 1));
+        assert_eq!(*a, 1);
+      }
+    }
+
+    #[test]
+    fn dangling_weak_never_upgrades() {
+      static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+      struct DetectDrop;
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          NUM_DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      let w = Weak::<DetectDrop>::new();
+      assert!(w.upgrade().is_none());
+      let w2 = w.clone();
+      assert_eq!(w2.data().alloc_ref_count.load(Relaxed), 2);
+      assert!(w2.upgrade().is_none());
+      drop(w);
+      drop(w2);
+      // 初期化していないdataはdropされない
+      assert_eq!(NUM_DROPS.load(Relaxed), 0);
+      drop(Weak::<String>::default());
+    }
+
+    #[test]
+    fn shared_slice_across_threads() {
+      static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+      #[derive(Clone)]
+      struct DetectDrop(u64);
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          NUM_DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      let v: Vec<_> = (0..100).map(DetectDrop).collect();
+      let a = Arc::from_slice(&v);
+      drop(v);
+      assert_eq!(NUM_DROPS.load(Relaxed), 100);
+      assert_eq!(a.len(), 100);
+      assert_eq!(a[42].0, 42);
+
+      let w = Arc::downgrade(&a);
+      std::thread::scope(|s| {
+        for t in 0..4 {
+          let a = a.clone();
+          s.spawn(move || {
+            assert_eq!(a.iter().map(|x| x.0).sum::<u64>(), 4950);
+            assert_eq!(a[t * 10].0, t as u64 * 10);
+          });
+        }
+      });
+      assert_eq!(Arc::counts(&a), (1, 2));
+      assert!(Arc::ptr_eq(&a, &w.upgrade().unwrap()));
+      drop(a);
+      // 要素は1回ずつdropされ、メモリはWeakが消えたときに返される
+      assert_eq!(NUM_DROPS.load(Relaxed), 200);
+      assert!(w.upgrade().is_none());
+      drop(w);
+
+      // 空のスライスとゼロサイズの要素
+      let empty = Arc::<[u8]>::from_slice(&[]);
+      assert!(empty.is_empty());
+      let units = Arc::from_slice(&[(), (), ()]);
+      assert_eq!(units.len(), 3);
+
+      // 大きくアラインされた要素
+      #[derive(Clone, Copy)]
+      #[repr(align(64))]
+      struct Aligned(u8);
+      let aligned = Arc::from_slice(&[Aligned(1), Aligned(2)]);
+      assert_eq!(aligned.as_ptr() as usize % 64, 0);
+      assert_eq!(aligned[1].0, 2);
+    }
+
+    #[test]
+    fn formatting_forwards_to_value() {
+      let a = Arc::new(42);
+      assert_eq!(a.to_string(), "42");
+      assert_eq!(format!("{a:?}"), "42");
+      assert_eq!(format!("{:?}", Arc::new(String::from("s"))), "\"s\"");
+      assert_eq!(format!("{:>4}", a), "  42");
+
+      let w = Arc::downgrade(&a);
+      drop(a);
+      assert_eq!(format!("{w:?}"), "(Weak)");
+    }
+
+    #[test]
+    fn compare_by_value() {
+      use std::collections::{BTreeSet, HashSet};
+
+      let a = Arc::new(String::from("a"));
+      let a2 = Arc::new(String::from("a"));
+      let b = Arc::new(String::from("b"));
+      assert!(!Arc::ptr_eq(&a, &a2));
+      assert_eq!(a, a2);
+      assert_ne!(a, b);
+      assert!(a < b);
+
+      // 別々に確保しても、同じ値なら1つにまとまる
+      let set: HashSet<_> = [a.clone(), a2.clone(), b.clone(), a.clone()].into_iter().collect();
+      assert_eq!(set.len(), 2);
+      assert!(set.contains(&Arc::new(String::from("b"))));
+      let sorted: BTreeSet<_> = [b, a2, a].into_iter().collect();
+      assert_eq!(sorted.iter().map(|s| s.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn new_cyclic_self_reference() {
+      struct Node {
+        me: Weak<Node>,
+        value: u32,
+      }
+
+      let mut upgraded_during_init = None;
+      let node = Arc::new_cyclic(|me| {
+        upgraded_during_init = Some(me.upgrade().is_some());
+        Node { me: me.clone(), value: 7 }
+      });
+      assert_eq!(upgraded_during_init, Some(false));
+      let again = node.me.upgrade().unwrap();
+      assert!(Arc::ptr_eq(&node, &again));
+      assert_eq!(again.value, 7);
+      assert_eq!(Arc::counts(&node), (2, 2));
+
+      drop(again);
+      let me = node.me.clone();
+      drop(node);
+      assert!(me.upgrade().is_none());
+
+      // fがpanicしても書きかけのアロケーションは解放される
+      let r = std::panic::catch_unwind(|| Arc::<String>::new_cyclic(|_| panic!()));
+      assert!(r.is_err());
+    }
+
+    #[test]
+    fn upgrade_all_reports_dead() {
+      let a = Arc::new(0);
+      let b = Arc::new(1);
+      let c = Arc::new(2);
+      let weaks: Vec<_> = [&a, &b, &c].map(Arc::downgrade).into_iter().chain([Weak::new()]).collect();
+      drop(b);
+
+      let (live, dead) = Weak::upgrade_all(weaks.iter());
+      assert_eq!(live.iter().map(|a| **a).collect::<Vec<_>>(), [0, 2]);
+      assert_eq!(dead, [1, 3]);
+      assert_eq!(Arc::counts(&a).0, 2);
+    }
+
+    #[test]
+    fn ptr_eq_is_identity() {
+      // PartialEqを実装していない型
+      struct Opaque(#[allow(dead_code)] u32);
+
+      let a = Arc::new(Opaque(1));
+      let b = a.clone();
+      let c = Arc::new(Opaque(1));
+      assert!(Arc::ptr_eq(&a, &b));
+      assert!(!Arc::ptr_eq(&a, &c));
+
+      let (x, y) = (Arc::new(7), Arc::new(7));
+      assert_eq!(*x, *y);
+      assert!(!Arc::ptr_eq(&x, &y));
+
+      let wa = Arc::downgrade(&a);
+      let wb = Arc::downgrade(&b);
+      let wc = Arc::downgrade(&c);
+      assert!(Weak::ptr_eq(&wa, &wb));
+      assert!(!Weak::ptr_eq(&wa, &wc));
+      // 中身がdropされた後も同じ結果になる
+      drop((a, b));
+      assert!(Weak::ptr_eq(&wa, &wb.clone()));
+    }
+
+    #[test]
+    fn is_unique() {
+      let a = Arc::new(1);
+      assert!(Arc::is_unique(&a));
+      let b = a.clone();
+      assert!(!Arc::is_unique(&a));
+      drop(b);
+      let w = Arc::downgrade(&a);
+      assert!(!Arc::is_unique(&a));
+      drop(w);
+      assert!(Arc::is_unique(&a));
+      // 調べた後もカウントは元のまま
+      assert_eq!(Arc::counts(&a), (1, 1));
+    }
+
+    #[test]
+    fn try_unwrap_races_upgrade() {
+      let a = Arc::new(String::from("x"));
+      let b = a.clone();
+      let Err(a) = Arc::try_unwrap(a) else { panic!("shared Arc must not unwrap") };
+      drop(b);
+      assert_eq!(Arc::try_unwrap(a).ok().as_deref(), Some("x"));
+
+      for _ in 0..100 {
+        let a = Arc::new(String::from("x"));
+        let w = Arc::downgrade(&a);
+        let (unwrapped, upgraded) = std::thread::scope(|s| {
+          let t = s.spawn(|| w.upgrade());
+          let unwrapped = Arc::try_unwrap(a);
+          (unwrapped, t.join().unwrap())
+        });
+        // どちらか一方だけが勝つ
+        assert_ne!(unwrapped.is_ok(), upgraded.is_some());
+        if let Ok(v) = unwrapped {
+          assert_eq!(v, "x");
+          assert!(w.upgrade().is_none());
+        }
+      }
+    }
+
+    #[test]
+    fn weak_raw_round_trip() {
+      let a = Arc::new(5);
+      let raw = Weak::into_raw(Arc::downgrade(&a));
+      assert_eq!(unsafe { *raw }, 5);
+      // 所有権はポインタが持っている
+      assert_eq!(Arc::counts(&a), (1, 2));
+      let w = unsafe { Weak::from_raw(raw) };
+      assert_eq!(*w.upgrade().unwrap(), 5);
+
+      // 中身がなくなったWeakも往復でき、upgradeはNoneのまま
+      drop(a);
+      let raw = Weak::into_raw(w);
+      let w = unsafe { Weak::from_raw(raw) };
+      assert!(w.upgrade().is_none());
+      assert_eq!(w.data().alloc_ref_count.load(Relaxed), 1);
+
+      // Weak::newもヘッダ付きで確保しているので、そのまま往復できる
+      let raw = Weak::into_raw(Weak::<String>::new());
+      let w = unsafe { Weak::from_raw(raw) };
+      assert!(w.upgrade().is_none());
+      let w2 = w.clone();
+      assert_eq!(w.data().alloc_ref_count.load(Relaxed), 2);
+      drop(w2);
+      assert_eq!(w.data().alloc_ref_count.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn unwrap_or_clone_clones_only_when_shared() {
+      static CLONES: AtomicUsize = AtomicUsize::new(0);
+      struct Counted(u32);
+      impl Clone for Counted {
+        fn clone(&self) -> Self {
+          CLONES.fetch_add(1, Relaxed);
+          Counted(self.0)
+        }
+      }
+
+      let a = Arc::new(Counted(1));
+      assert_eq!(Arc::unwrap_or_clone(a).0, 1);
+      assert_eq!(CLONES.load(Relaxed), 0);
+
+      let a = Arc::new(Counted(2));
+      let b = a.clone();
+      assert_eq!(Arc::unwrap_or_clone(a).0, 2);
+      assert_eq!(CLONES.load(Relaxed), 1);
+      assert_eq!(Arc::counts(&b), (1, 1));
+    }
+
+    #[test]
+    fn into_inner_with_outstanding_weak() {
+      static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+      struct DetectDrop;
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          NUM_DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      let a = Arc::new(DetectDrop);
+      let b = a.clone();
+      let w = Arc::downgrade(&a);
+      assert!(Arc::into_inner(a).is_none());
+      let value = Arc::into_inner(b).unwrap();
+      // 返されただけでdropはされていない。アロケーションはwが持っている
+      assert_eq!(NUM_DROPS.load(Relaxed), 0);
+      assert!(w.upgrade().is_none());
+      assert_eq!(w.data().alloc_ref_count.load(Relaxed), 1);
+      drop(value);
+      assert_eq!(NUM_DROPS.load(Relaxed), 1);
+      drop(w);
+      assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn arc_raw_round_trip() {
+      let a = Arc::new(String::from("ffi"));
+      let b = a.clone();
+      let raw = Arc::into_raw(a);
+      assert_eq!(raw, Arc::as_ptr(&b));
+      assert_eq!(unsafe { &*raw }, "ffi");
+      // ポインタが所有権を持っているので数は変わらない
+      assert_eq!(Arc::counts(&b), (2, 1));
+
+      let a = unsafe { Arc::from_raw(raw) };
+      assert!(Arc::ptr_eq(&a, &b));
+      assert_eq!(*a, "ffi");
+      assert_eq!(Arc::counts(&b), (2, 1));
+      drop(a);
+      assert_eq!(Arc::counts(&b), (1, 1));
+
+      let s = Arc::from_slice(&[1, 2, 3]);
+      assert_eq!(unsafe { &*Arc::as_ptr(&s) }, [1, 2, 3]);
+    }
+
+    #[test]
+    fn raw_strong_count_balanced() {
+      static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+      struct DetectDrop;
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          NUM_DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      let raw = Arc::into_raw(Arc::new(DetectDrop));
+      unsafe { Arc::increment_strong_count(raw) };
+      let a = unsafe { Arc::from_raw(raw) };
+      assert_eq!(Arc::counts(&a), (2, 1));
+      drop(a);
+      assert_eq!(NUM_DROPS.load(Relaxed), 0);
+      unsafe { Arc::decrement_strong_count(raw) };
+      assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn data_offset_round_trip() {
+      let a = Arc::new((1u8, 2u64));
+      let header = a.ptr.as_ptr() as *const ArcData<(u8, u64)>;
+      // UnsafeCellとManuallyDropはrepr(transparent)なので、Tはdataフィールドの位置にある
+      let data = a.data().data.get() as *const (u8, u64);
+      assert_eq!(data as usize - header as usize, ArcData::<(u8, u64)>::DATA_OFFSET);
+      assert_eq!(ArcData::from_data_ptr(data), header);
+      assert_eq!(ArcData::from_data_ptr(&*a as *const _), header);
+    }
+
+    #[test]
+    fn upgrade_at_max_strong_count() {
+      let a = Arc::new(1);
+      let w = Arc::downgrade(&a);
+
+      unsafe { Arc::__set_strong_count(&a, usize::MAX - 1); }
+      // 上限の1つ手前まではupgradeできる
+      let b = w.upgrade().unwrap();
+      assert_eq!(a.data().data_ref_count.load(Relaxed), usize::MAX);
+
+      // usize::MAXからのupgradeはassertで止まる
+      let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| w.upgrade()));
+      assert!(r.is_err());
+      assert_eq!(a.data().data_ref_count.load(Relaxed), usize::MAX);
+
+      unsafe { Arc::__set_strong_count(&a, 2); }
+      drop(b);
+      drop(a);
+      assert!(w.upgrade().is_none());
+    }
+
+
+    #[test]
+    fn default_and_from() {
+      let a: Arc<Vec<u8>> = Default::default();
+      assert!(a.is_empty());
+      assert_eq!(Arc::strong_count(&a), 1);
+      let b: Arc<i32> = 5.into();
+      assert_eq!(*b, 5);
+    }
+
+
+    #[test]
+    fn new_uninit_then_assume_init() {
+      let mut u = Arc::<[u64; 4]>::new_uninit();
+      Arc::get_mut(&mut u).unwrap().write([1, 2, 3, 4]);
+      let a = unsafe { Arc::assume_init(u) };
+      let b = a.clone();
+      let sum = std::thread::spawn(move || b.iter().sum::<u64>()).join().unwrap();
+      assert_eq!(sum, 10);
+      assert_eq!(Arc::strong_count(&a), 1);
+    }
+
+
+    #[test]
+    fn downgrade_near_limit_does_not_panic() {
+      let a = Arc::new(1);
+      let weaks: Vec<_> = (0..1000).map(|_| Arc::downgrade(&a)).collect();
+      // 大量にcloneした状態を作る。上限ちょうどまではpanicせずに増やせる
+      a.data().alloc_ref_count.store(usize::MAX / 2, Relaxed);
+      let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Arc::downgrade(&a)));
+      let w = r.unwrap();
+      assert_eq!(a.data().alloc_ref_count.load(Relaxed), usize::MAX / 2 + 1);
+
+      // 本当の数に戻してから片付ける
+      a.data().alloc_ref_count.store(1 + weaks.len() + 1, Relaxed);
+      drop(weaks);
+      drop(w);
+      assert_eq!(Arc::weak_count(&a), 0);
+    }
+
+
+    #[test]
+    fn downgrade_with_backs_off_while_locked() {
+      use std::sync::Mutex;
+
+      struct Recording(Mutex<Vec<u32>>);
+      impl RetryPolicy for Recording {
+        fn backoff(&self, attempt: u32) {
+          self.0.lock().unwrap().push(attempt);
+          std::thread::yield_now();
+        }
+      }
+
+      let a = Arc::new(1);
+      let policy = Recording(Mutex::new(Vec::new()));
+      // get_mutがロックしている状態
+      a.data().alloc_ref_count.store(usize::MAX, Relaxed);
+      let w = std::thread::scope(|s| {
+        let t = s.spawn(|| Arc::downgrade_with(&a, &policy));
+        while policy.0.lock().unwrap().len() < 3 {
+          std::thread::yield_now();
+        }
+        a.data().alloc_ref_count.store(1, Release);
+        t.join().unwrap()
+      });
+      let attempts = policy.0.into_inner().unwrap();
+      assert!(attempts.iter().copied().eq(0..attempts.len() as u32));
+      assert_eq!(Arc::weak_count(&a), 1);
+      assert_eq!(*w.upgrade().unwrap(), 1);
+    }
+
+
+    #[test]
+    fn map_lookup_by_borrowed_key() {
+      let mut map = std::collections::HashMap::new();
+      map.insert(Arc::new(String::from("key")), 1);
+      assert_eq!(map.get(&String::from("key")), Some(&1));
+      assert_eq!(map.get(&String::from("other")), None);
+      let a = Arc::new(String::from("x"));
+      let s: &String = a.as_ref();
+      assert_eq!(s, "x");
+    }
+
+
+    #[test]
+    fn from_box_holds_trait_objects() {
+      let log = &std::cell::RefCell::new(Vec::new());
+      let shared = Arc::new(String::from("shared"));
+      let big = [1u64, 2, 3, 4];
+      let s = shared.clone();
+      let fs: Vec<Arc<dyn Fn() + '_>> = vec![
+        Arc::from_box(Box::new(|| log.borrow_mut().push(String::from("unit")))),
+        Arc::from_box(Box::new(move || log.borrow_mut().push(big.iter().sum::<u64>().to_string()))),
+        Arc::from_box(Box::new(move || log.borrow_mut().push(s.to_string()))),
+      ];
+      let copies = fs.clone();
+      for f in &copies {
+        f();
+      }
+      assert_eq!(*log.borrow(), ["unit", "10", "shared"]);
+      assert_eq!(Arc::strong_count(&shared), 2);
+      // 最後のArcで、ムーブしたクロージャがキャプチャしたものもdropされる
+      drop(fs);
+      drop(copies);
+      assert_eq!(Arc::strong_count(&shared), 1);
+
+      let z: Arc<dyn fmt::Debug> = Arc::from_box(Box::new(()));
+      assert_eq!(format!("{:?}", z), "()");
+    }
+
+
+    #[test]
+    fn weak_counts_without_upgrade() {
+      let a = Arc::new(1);
+      let w = Arc::downgrade(&a);
+      let w2 = w.clone();
+      assert_eq!(w.strong_count(), 1);
+      assert_eq!(w.weak_count(), 2);
+      drop(a);
+      assert_eq!(w.strong_count(), 0);
+      assert_eq!(w.weak_count(), 2);
+      drop(w2);
+      assert_eq!(w.weak_count(), 1);
+    }
+
+
+    #[test]
+    fn weak_default_never_upgrades() {
+      assert!(Weak::<i32>::default().upgrade().is_none());
+      // Weakを持つ構造体にもDefaultをderiveできる
+      #[derive(Default)]
+      struct Parent {
+        child: Weak<i32>,
+      }
+      let p = Parent::default();
+      assert!(p.child.upgrade().is_none());
+      assert_eq!(p.child.weak_count(), 1);
+    }
+
+
+    #[test]
+    fn from_iter_exact_moves_into_one_allocation() {
+      let a = Arc::<[u64]>::from_iter_exact((0..100u32).map(u64::from));
+      assert_eq!(a.len(), 100);
+      assert!(a.iter().copied().eq(0..100u64));
+      // 要素はヘッダのすぐ後ろにある。別に確保したVecを指してはいない
+      let offset = std::mem::offset_of!(ArcData<[u64; 0]>, data);
+      assert_eq!(Arc::as_ptr(&a) as *const u8 as usize - a.ptr.as_ptr() as *const u8 as usize, offset);
+      assert_eq!(Arc::counts(&a), (1, 1));
+
+      // 確保できない長さならErrを返し、要素は1つも読まない
+      assert_eq!(Arc::<[usize]>::try_from_iter_exact(0..usize::MAX).unwrap_err(), AllocError);
+    }
+
+
+    #[test]
+    fn on_last_drop_runs_once() {
+      static CALLS: AtomicUsize = AtomicUsize::new(0);
+      let a = Arc::new(String::from("x"));
+      Arc::on_last_drop(&a, || { CALLS.fetch_add(1, Relaxed); }).unwrap();
+      assert_eq!(Arc::on_last_drop(&a, || {}), Err(LastDropAlreadySet));
+      let w = Arc::downgrade(&a);
+      let clones: Vec<_> = (0..4).map(|_| a.clone()).collect();
+      std::thread::scope(|s| {
+        for c in clones {
+          s.spawn(move || drop(c));
+        }
+      });
+      assert_eq!(CALLS.load(Relaxed), 0);
+      drop(a);
+      assert_eq!(CALLS.load(Relaxed), 1);
+      // Weakが残っていても、もう呼ばれない
+      assert!(w.upgrade().is_none());
+      drop(w);
+      assert_eq!(CALLS.load(Relaxed), 1);
+    }
+
 }
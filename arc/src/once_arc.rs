@@ -0,0 +1,161 @@
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicU32};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+use atomic_wait::{wait, wake_all};
+
+use crate::{Arc, ArcData};
+
+// stateの値
+const INCOMPLETE: u32 = 0;
+// どれかのスレッドがfを実行している
+const RUNNING: u32 = 1;
+// fを実行していて、stateで寝ているスレッドがいる
+const QUEUED: u32 = 2;
+const COMPLETE: u32 = 3;
+
+// 一度だけ初期化されるArc。初期化後はcloneを配るだけ
+pub struct OnceArc<T> {
+  // nullなら未初期化。OnceArc自身がstrong参照を1つ持つ
+  ptr: AtomicPtr<ArcData<T>>,
+  // fを実行するスレッドを1つに絞り、他のスレッドはここで寝て待つ
+  state: AtomicU32,
+  // Send/SyncはArc<T>と同じ条件にする
+  _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> OnceArc<T> {
+  pub const fn new() -> Self {
+    Self {
+      ptr: AtomicPtr::new(ptr::null_mut()),
+      state: AtomicU32::new(INCOMPLETE),
+      _marker: PhantomData,
+    }
+  }
+
+  pub fn get(&self) -> Option<Arc<T>> {
+    let p = NonNull::new(self.ptr.load(Acquire))?;
+    // OnceArcが持っている参照を借りてcloneする
+    let arc = ManuallyDrop::new(Arc { ptr: p });
+    Some(Arc::clone(&arc))
+  }
+
+  pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> Arc<T> {
+    if let Some(arc) = self.get() {
+      return arc;
+    }
+    let mut s = self.state.load(Acquire);
+    loop {
+      match s {
+        // ptrはCOMPLETEにする前に書かれている
+        COMPLETE => return self.get().unwrap(),
+        INCOMPLETE => match self.state.compare_exchange(INCOMPLETE, RUNNING, Acquire, Acquire) {
+          Ok(_) => break,
+          Err(e) => s = e,
+        },
+        _ => {
+          // 寝ることを知らせてから寝る。fを実行しているスレッドは、終わるときにQUEUEDなら起こす
+          if s == RUNNING {
+            if let Err(e) = self.state.compare_exchange(RUNNING, QUEUED, Relaxed, Acquire) {
+              s = e;
+              continue;
+            }
+          }
+          wait(&self.state, QUEUED);
+          s = self.state.load(Acquire);
+        }
+      }
+    }
+
+    // fがpanicしたら他のスレッドが初期化をやり直せるように戻し、寝ているスレッドを起こす
+    struct Reset<'a>(&'a AtomicU32);
+    impl Drop for Reset<'_> {
+      fn drop(&mut self) {
+        if self.0.swap(INCOMPLETE, Release) == QUEUED {
+          wake_all(self.0);
+        }
+      }
+    }
+    let reset = Reset(&self.state);
+
+    let arc = ManuallyDrop::new(Arc::new(f()));
+    self.ptr.store(arc.ptr.as_ptr(), Release);
+    std::mem::forget(reset);
+    if self.state.swap(COMPLETE, Release) == QUEUED {
+      wake_all(&self.state);
+    }
+    Arc::clone(&arc)
+  }
+}
+
+impl<T> Default for OnceArc<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> Drop for OnceArc<T> {
+  fn drop(&mut self) {
+    if let Some(p) = NonNull::new(*self.ptr.get_mut()) {
+      drop(Arc { ptr: p });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn get_or_init_race() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let once = OnceArc::new();
+
+    let arcs: Vec<Arc<String>> = thread::scope(|s| {
+      let handles: Vec<_> = (0..16)
+        .map(|_| s.spawn(|| once.get_or_init(|| {
+          CALLS.fetch_add(1, Relaxed);
+          thread::sleep(std::time::Duration::from_millis(10));
+          String::from("config")
+        })))
+        .collect();
+      handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    assert_eq!(CALLS.load(Relaxed), 1);
+    for a in &arcs {
+      assert_eq!(**a, "config");
+      assert!(ptr::eq(&**a, &*arcs[0]));
+    }
+  }
+
+  #[test]
+  fn waiter_retries_after_panicking_init() {
+    let once = OnceArc::new();
+    let got = thread::scope(|s| {
+      let first = s.spawn(|| {
+        once.get_or_init(|| {
+          // 他のスレッドがスピンせずにstateで寝るまで待ってからpanicする
+          while once.state.load(Relaxed) != QUEUED {
+            thread::yield_now();
+          }
+          panic!("init failed");
+        })
+      });
+      while once.state.load(Relaxed) != RUNNING {
+        thread::yield_now();
+      }
+      let second = s.spawn(|| *once.get_or_init(|| 2));
+      assert!(first.join().is_err());
+      // 寝ていたスレッドが起こされて、初期化をやり直す
+      second.join().unwrap()
+    });
+    assert_eq!(got, 2);
+    assert_eq!(once.state.load(Relaxed), COMPLETE);
+  }
+}
@@ -0,0 +1,79 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Release, SeqCst};
+
+/// AtomicArcで、loadがポインタを読んでからcloneし終えるまでの間を守る方法
+///
+/// swapは入れ替えた後にsynchronizeを呼び、古いポインタを読んだかもしれないloadがいなくなるまで待つ
+///
+/// # Safety
+///
+/// synchronizeが戻った時点で、その呼び出しより前にenterしてまだleaveしていないものが残っていてはいけない。
+/// 待たずに戻ると、swapが返した古いArcがdropされた後にloadがcloneしてuse-after-freeになる
+pub unsafe trait Reclaim {
+  // ポインタを読む前に呼ぶ。戻り値はleaveにそのまま渡す
+  fn enter(&self) -> usize;
+  fn leave(&self, token: usize);
+  // ポインタを入れ替えた後に呼ぶ。swapどうしはAtomicArcが直列にする
+  fn synchronize(&self);
+}
+
+// 読んでいる途中のloadを1つのカウンタで数える
+// loadが安く、読み手が少ないときに向く。読み手が途切れないとswapがいつまでも待たされる
+#[derive(Debug, Default)]
+pub struct GuardCount {
+  readers: AtomicUsize,
+}
+
+unsafe impl Reclaim for GuardCount {
+  fn enter(&self) -> usize {
+    self.readers.fetch_add(1, SeqCst);
+    0
+  }
+
+  fn leave(&self, _token: usize) {
+    self.readers.fetch_sub(1, Release);
+  }
+
+  // 0になった後に増やしたloadは、入れ替えた後のポインタを読む
+  fn synchronize(&self) {
+    while self.readers.load(SeqCst) != 0 {
+      std::hint::spin_loop();
+    }
+  }
+}
+
+// epochの偶奇でカウンタを2つに分け、swapは古いepochのloadだけを待つ
+// loadはepochを2回読むぶん重いが、読み手が多く途切れなくてもswapが先に進める
+#[derive(Debug, Default)]
+pub struct Epoch {
+  // swapのたびに1増える
+  epoch: AtomicUsize,
+  readers: [AtomicUsize; 2],
+}
+
+unsafe impl Reclaim for Epoch {
+  fn enter(&self) -> usize {
+    loop {
+      let e = self.epoch.load(SeqCst);
+      let slot = e % 2;
+      self.readers[slot].fetch_add(1, SeqCst);
+      // 増やす前にswapがepochを進めていたら、そのswapは自分を待っていない
+      if self.epoch.load(SeqCst) == e {
+        return slot;
+      }
+      self.readers[slot].fetch_sub(1, Release);
+    }
+  }
+
+  fn leave(&self, token: usize) {
+    self.readers[token].fetch_sub(1, Release);
+  }
+
+  // これ以降にepochを読んだloadは新しいポインタを見る。古いepochのloadだけを待つ
+  fn synchronize(&self) {
+    let e = self.epoch.fetch_add(1, SeqCst) % 2;
+    while self.readers[e].load(SeqCst) != 0 {
+      std::hint::spin_loop();
+    }
+  }
+}
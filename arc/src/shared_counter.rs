@@ -0,0 +1,66 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::Arc;
+
+// Arc<AtomicUsize>のよくある使い方をまとめたもの。cloneしたものは同じカウンタを共有する
+// 数を数えるだけなので、他のメモリへのアクセスとの順序は保証しない(Relaxed)
+#[derive(Clone)]
+pub struct SharedCounter(Arc<AtomicUsize>);
+
+impl SharedCounter {
+  pub fn new(initial: usize) -> Self {
+    Self(Arc::new(AtomicUsize::new(initial)))
+  }
+
+  // 増やした後の値を返す
+  pub fn inc(&self) -> usize {
+    self.0.fetch_add(1, Relaxed) + 1
+  }
+
+  // 減らした後の値を返す。0から減らすとpanicする
+  pub fn dec(&self) -> usize {
+    let old = self.0
+      .fetch_update(Relaxed, Relaxed, |n| n.checked_sub(1))
+      .expect("SharedCounter::dec below zero");
+    old - 1
+  }
+
+  pub fn get(&self) -> usize {
+    self.0.load(Relaxed)
+  }
+}
+
+impl Default for SharedCounter {
+  fn default() -> Self {
+    Self::new(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn clones_share_one_counter() {
+    let counter = SharedCounter::default();
+    let handles: Vec<_> = (0..4)
+      .map(|_| {
+        let c = counter.clone();
+        thread::spawn(move || {
+          for _ in 0..100 {
+            c.inc();
+          }
+        })
+      })
+      .collect();
+    for h in handles {
+      h.join().unwrap();
+    }
+    assert_eq!(counter.get(), 400);
+    assert_eq!(counter.dec(), 399);
+    assert_eq!(counter.inc(), 400);
+  }
+}
@@ -0,0 +1,86 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+
+use xorshift::XorShift;
+
+use crate::{Arc, Weak};
+
+struct Payload<'a> {
+  value: u64,
+  drops: &'a AtomicUsize,
+}
+
+impl Drop for Payload<'_> {
+  fn drop(&mut self) {
+    self.drops.fetch_add(1, Relaxed);
+  }
+}
+
+// clone/drop/downgrade/upgradeをランダムに繰り返し、
+// 値が壊れないこと、カウントが元に戻ること、データが1回だけdropされることを確かめる
+pub fn stress_arc(threads: usize, iters: usize) -> Result<(), String> {
+  const VALUE: u64 = 0x5eed;
+  let drops = AtomicUsize::new(0);
+  let root = Arc::new(Payload { value: VALUE, drops: &drops });
+  let weak = Arc::downgrade(&root);
+
+  thread::scope(|s| {
+    let handles: Vec<_> = (0..threads)
+      .map(|_| {
+        let root = &root;
+        s.spawn(move || -> Result<(), String> {
+          let mut rng = XorShift::new();
+          let mut strong: Vec<Arc<Payload>> = Vec::new();
+          let mut weak: Vec<Weak<Payload>> = Vec::new();
+          for _ in 0..iters {
+            match rng.next_u64() % 5 {
+              0 => strong.push(root.clone()),
+              1 => drop(strong.pop()),
+              2 => weak.push(Arc::downgrade(root)),
+              3 => drop(weak.pop()),
+              _ => {
+                if let Some(w) = weak.last() {
+                  match w.upgrade() {
+                    Some(a) => strong.push(a),
+                    None => return Err("upgrade failed while a strong reference exists".into()),
+                  }
+                }
+              }
+            }
+            if let Some(a) = strong.last() {
+              if a.value != VALUE {
+                return Err(format!("value corrupted: {:#x}", a.value));
+              }
+            }
+          }
+          Ok(())
+        })
+      })
+      .collect();
+    handles.into_iter().try_for_each(|h| h.join().map_err(|_| "thread panicked".to_string())?)
+  })?;
+
+  let counts = Arc::counts(&root);
+  if counts != (1, 2) {
+    return Err(format!("counts after join: {counts:?}, expected (1, 2)"));
+  }
+  drop(root);
+  if drops.load(Relaxed) != 1 {
+    return Err(format!("payload dropped {} times", drops.load(Relaxed)));
+  }
+  if weak.upgrade().is_some() {
+    return Err("upgrade succeeded after the last Arc was dropped".into());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn smoke() {
+    assert_eq!(stress_arc(4, 1000), Ok(()));
+  }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use crate::{Arc, Weak};
+
+// 子へはArc、親へはWeakで辿る木。親子が互いにArcを持つと循環してリークするので、上向きは弱参照にする
+pub struct TreeNode<T> {
+  pub value: T,
+  // rootならNone
+  parent: Option<Weak<TreeNode<T>>>,
+  children: Mutex<Vec<Arc<TreeNode<T>>>>,
+}
+
+impl<T> TreeNode<T> {
+  pub fn new_root(value: T) -> Arc<Self> {
+    Arc::new(Self { value, parent: None, children: Mutex::new(Vec::new()) })
+  }
+
+  // 親のWeakを設定した子を作ってchildrenに加える。返すArcは子を指す
+  // このArcは自前の型でself: &Arc<Self>にできないので、Arc::downgradeと同じく関連関数にする
+  pub fn add_child(node: &Arc<Self>, value: T) -> Arc<Self> {
+    let child = Arc::new(Self {
+      value,
+      parent: Some(Arc::downgrade(node)),
+      children: Mutex::new(Vec::new()),
+    });
+    node.children.lock().unwrap().push(child.clone());
+    child
+  }
+
+  // 親がもうdropされていればNone
+  pub fn parent(&self) -> Option<Arc<Self>> {
+    self.parent.as_ref()?.upgrade()
+  }
+
+  pub fn children(&self) -> Vec<Arc<Self>> {
+    self.children.lock().unwrap().clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering::Relaxed;
+
+  use super::*;
+
+  #[test]
+  fn drop_root_frees_every_node() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct DetectDrop(&'static str);
+    impl Drop for DetectDrop {
+      fn drop(&mut self) {
+        NUM_DROPS.fetch_add(1, Relaxed);
+      }
+    }
+
+    let root = TreeNode::new_root(DetectDrop("root"));
+    let a = TreeNode::add_child(&root, DetectDrop("a"));
+    TreeNode::add_child(&root, DetectDrop("b"));
+    let a1 = TreeNode::add_child(&a, DetectDrop("a1"));
+
+    // 子から親へWeakを辿れる
+    assert_eq!(a1.parent().unwrap().value.0, "a");
+    assert_eq!(a1.parent().unwrap().parent().unwrap().value.0, "root");
+    assert!(root.parent().is_none());
+    let names: Vec<_> = root.children().iter().map(|c| c.value.0).collect();
+    assert_eq!(names, ["a", "b"]);
+
+    let leaf = Arc::downgrade(&a1);
+    drop((a, a1));
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    drop(root);
+    // 上向きがWeakなので循環せず、全てのノードが解放される
+    assert_eq!(NUM_DROPS.load(Relaxed), 4);
+    assert!(leaf.upgrade().is_none());
+  }
+}
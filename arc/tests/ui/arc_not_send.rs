@@ -0,0 +1,10 @@
+use std::cell::Cell;
+
+use arc::Arc;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+  // Arcは中身を共有するので、送るにはT: Syncが必要
+  assert_send::<Arc<Cell<i32>>>();
+}
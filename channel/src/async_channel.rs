@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use arc::Arc;
+use mutex::Mutex;
+
+struct State<T> {
+  queue: VecDeque<T>,
+  cap: usize,
+  senders: usize,
+  receiver_alive: bool,
+  // 空きを待っているsendのidとwaker。先頭から順に起こす
+  // 1つのsendにつき高々1つで、終わったときとdropされたときに取り除く
+  send_waiters: VecDeque<(u64, Waker)>,
+  next_id: u64,
+  // 値を待っているrecv。recvは&mut selfを取るので同時に1つしかない
+  recv_waiter: Option<Waker>,
+}
+
+// 容量capの非同期チャネル。いっぱいならsendが、空ならrecvがwakerを登録してexecutorに戻る
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(cap > 0, "bounded channel needs a positive capacity");
+  let state = Arc::new(Mutex::new(State {
+    queue: VecDeque::with_capacity(cap),
+    cap,
+    senders: 1,
+    receiver_alive: true,
+    send_waiters: VecDeque::new(),
+    next_id: 0,
+    recv_waiter: None,
+  }));
+  (Sender { state: state.clone() }, Receiver { state })
+}
+
+pub struct Sender<T> {
+  state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+  // いっぱいなら空くまで待つ。Receiverがdropされていれば値を返す
+  pub async fn send(&self, value: T) -> Result<(), T> {
+    SendFuture { sender: self, value: Some(value), id: None }.await
+  }
+}
+
+impl<T> State<T> {
+  fn position(&self, id: u64) -> Option<usize> {
+    self.send_waiters.iter().position(|(i, _)| *i == id)
+  }
+}
+
+struct SendFuture<'a, T> {
+  sender: &'a Sender<T>,
+  value: Option<T>,
+  // 一度でもPendingを返していれば、send_waitersに登録したときのid
+  id: Option<u64>,
+}
+
+// valueをピン留めしたまま使うことはないので、Tに関係なく動かしてよい
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+  type Output = Result<(), T>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = &mut *self;
+    let mut state = this.sender.state.lock();
+    if !state.receiver_alive {
+      return Poll::Ready(Err(this.value.take().unwrap()));
+    }
+    if state.queue.len() == state.cap {
+      // 何度pollされてもエントリは1つ。残っていればwakerだけ差し替え、起こされて消えていれば並び直す
+      match this.id.and_then(|id| state.position(id)) {
+        Some(i) => {
+          let w = &mut state.send_waiters[i].1;
+          if !w.will_wake(cx.waker()) {
+            *w = cx.waker().clone();
+          }
+        }
+        None => {
+          let id = *this.id.get_or_insert_with(|| {
+            state.next_id += 1;
+            state.next_id
+          });
+          state.send_waiters.push_back((id, cx.waker().clone()));
+        }
+      }
+      return Poll::Pending;
+    }
+    // 起こされる前に空きを見つけたなら、自分のエントリが残っている
+    if let Some(i) = this.id.take().and_then(|id| state.position(id)) {
+      state.send_waiters.remove(i);
+    }
+    state.queue.push_back(this.value.take().unwrap());
+    let receiver = state.recv_waiter.take();
+    drop(state);
+    if let Some(w) = receiver {
+      w.wake();
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+  fn drop(&mut self) {
+    let Some(id) = self.id else {
+      return;
+    };
+    let mut state = self.sender.state.lock();
+    let next = match state.position(id) {
+      Some(i) => {
+        state.send_waiters.remove(i);
+        None
+      }
+      // 起こされた後に送らずキャンセルされたら、空きを次のsendに回す
+      None if state.queue.len() < state.cap => state.send_waiters.pop_front(),
+      None => None,
+    };
+    drop(state);
+    if let Some((_, w)) = next {
+      w.wake();
+    }
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.state.lock().senders += 1;
+    Self { state: self.state.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.state.lock();
+    state.senders -= 1;
+    // 最後のSenderなら、待っているrecvにNoneを返させる
+    let receiver = if state.senders == 0 { state.recv_waiter.take() } else { None };
+    drop(state);
+    if let Some(w) = receiver {
+      w.wake();
+    }
+  }
+}
+
+pub struct Receiver<T> {
+  state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+  // 空なら届くまで待つ。空のままSenderがすべてdropされたらNone
+  // recv_waiterは1つしか持てないので、recvを同時に2つ待たせないよう&mut selfを取る
+  pub async fn recv(&mut self) -> Option<T> {
+    poll_fn(|cx| {
+      let mut state = self.state.lock();
+      if let Some(value) = state.queue.pop_front() {
+        // 1つ空いたので、待っているsendを1つ起こす
+        let sender = state.send_waiters.pop_front();
+        drop(state);
+        if let Some((_, w)) = sender {
+          w.wake();
+        }
+        return Poll::Ready(Some(value));
+      }
+      if state.senders == 0 {
+        return Poll::Ready(None);
+      }
+      state.recv_waiter = Some(cx.waker().clone());
+      Poll::Pending
+    })
+    .await
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.state.lock();
+    state.receiver_alive = false;
+    // 待っているsendはもう空きを待っても仕方がないので、全部起こしてErrを返させる
+    let senders = std::mem::take(&mut state.send_waiters);
+    drop(state);
+    for (_, w) in senders {
+      w.wake();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::future::Future;
+  use std::pin::Pin;
+  use std::sync::atomic::{AtomicBool, AtomicUsize};
+  use std::sync::atomic::Ordering::Relaxed;
+  use std::task::{Context, Wake};
+
+  use super::*;
+
+  struct Woken(AtomicBool);
+
+  impl Wake for Woken {
+    fn wake(self: std::sync::Arc<Self>) {
+      self.0.store(true, Relaxed);
+    }
+  }
+
+  // 起こされたタスクだけをpollする。誰も起こされないのに残っていたらデッドロック
+  fn run(tasks: Vec<Pin<Box<dyn Future<Output = ()> + '_>>>) {
+    let mut tasks: Vec<_> = tasks
+      .into_iter()
+      .map(|t| (t, std::sync::Arc::new(Woken(AtomicBool::new(true)))))
+      .collect();
+    while !tasks.is_empty() {
+      let mut progressed = false;
+      tasks.retain_mut(|(task, woken)| {
+        if !woken.0.swap(false, Relaxed) {
+          return true;
+        }
+        progressed = true;
+        let waker = Waker::from(woken.clone());
+        task.as_mut().poll(&mut Context::from_waker(&waker)).is_pending()
+      });
+      assert!(progressed, "no task was woken");
+    }
+  }
+
+  // 一度だけPendingを返してexecutorに戻る
+  struct YieldNow(bool);
+
+  impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+      if self.0 {
+        return Poll::Ready(());
+      }
+      self.0 = true;
+      cx.waker().wake_by_ref();
+      Poll::Pending
+    }
+  }
+
+  #[test]
+  fn slow_consumer_applies_backpressure() {
+    const ITEMS: usize = 50;
+    let (tx, mut rx) = bounded(2);
+    let sent = AtomicUsize::new(0);
+    let received = std::cell::RefCell::new(Vec::new());
+    let max_ahead = AtomicUsize::new(0);
+
+    let producer = async {
+      for i in 0..ITEMS {
+        tx.send(i).await.unwrap();
+        sent.fetch_add(1, Relaxed);
+        let ahead = sent.load(Relaxed) - received.borrow().len();
+        max_ahead.fetch_max(ahead, Relaxed);
+      }
+      drop(tx);
+    };
+    let consumer = async {
+      while let Some(v) = rx.recv().await {
+        received.borrow_mut().push(v);
+        // 受け取るたびに何度かexecutorに戻り、producerを先に走らせる
+        for _ in 0..3 {
+          YieldNow(false).await;
+        }
+      }
+    };
+    run(vec![Box::pin(producer), Box::pin(consumer)]);
+
+    assert_eq!(*received.borrow(), (0..ITEMS).collect::<Vec<_>>());
+    // 容量2 + 受け取った直後に空いた1つより先には進めない
+    assert!(max_ahead.load(Relaxed) <= 3);
+  }
+
+  #[test]
+  fn send_fails_after_receiver_dropped() {
+    let (tx, rx) = bounded(1);
+    let tx2 = tx.clone();
+    let result = std::cell::Cell::new(None);
+    run(vec![Box::pin(async {
+      tx.send(1).await.unwrap();
+      drop(rx);
+      result.set(Some(tx2.send(2).await));
+    })]);
+    assert_eq!(result.take(), Some(Err(2)));
+  }
+
+  #[test]
+  fn repolled_sender_does_not_strand_next() {
+    let (tx, mut rx) = bounded(1);
+    let woken_a = std::sync::Arc::new(Woken(AtomicBool::new(false)));
+    let woken_b = std::sync::Arc::new(Woken(AtomicBool::new(false)));
+    let waker_a = Waker::from(woken_a.clone());
+    let waker_b = Waker::from(woken_b.clone());
+    let mut cx_a = Context::from_waker(&waker_a);
+    let mut cx_b = Context::from_waker(&waker_b);
+    let recv = |rx: &mut Receiver<i32>| {
+      let mut f = Box::pin(rx.recv());
+      match f.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(v) => v,
+        Poll::Pending => panic!("queue should not be empty"),
+      }
+    };
+
+    run(vec![Box::pin(async { tx.send(0).await.unwrap() })]);
+    let mut a = Box::pin(tx.send(1));
+    let mut b = Box::pin(tx.send(2));
+    // 空く前にaを2回pollしても、登録は1つのまま
+    assert!(a.as_mut().poll(&mut cx_a).is_pending());
+    assert!(a.as_mut().poll(&mut cx_a).is_pending());
+    assert!(b.as_mut().poll(&mut cx_b).is_pending());
+
+    assert_eq!(recv(&mut rx), Some(0));
+    assert!(woken_a.0.load(Relaxed));
+    assert!(a.as_mut().poll(&mut cx_a).is_ready());
+    // aの古いエントリが残っていると、ここでbではなくそれが起こされる
+    assert_eq!(recv(&mut rx), Some(1));
+    assert!(woken_b.0.load(Relaxed));
+    assert!(b.as_mut().poll(&mut cx_b).is_ready());
+    assert_eq!(recv(&mut rx), Some(2));
+  }
+
+  #[test]
+  fn cancelled_sender_passes_wakeup_on() {
+    let (tx, mut rx) = bounded(1);
+    let woken = std::sync::Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    let noop = &mut Context::from_waker(Waker::noop());
+
+    run(vec![Box::pin(async { tx.send(0).await.unwrap() })]);
+    let mut a = Box::pin(tx.send(1));
+    let mut b = Box::pin(tx.send(2));
+    assert!(a.as_mut().poll(noop).is_pending());
+    assert!(b.as_mut().poll(&mut cx).is_pending());
+    assert!(matches!(Box::pin(rx.recv()).as_mut().poll(noop), Poll::Ready(Some(0))));
+    // 起こされたaが送らずに捨てられたら、bが起こされる
+    assert!(!woken.0.load(Relaxed));
+    drop(a);
+    assert!(woken.0.load(Relaxed));
+    assert!(b.as_mut().poll(&mut cx).is_ready());
+  }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+use condvar::Condvar;
+use mutex::Mutex;
+
+// 同じキーの値がキューに残っていれば、新しい値で置き換えるMPSCチャネル
+// receiverは最新の値だけを処理すればよい("redraw requested"のようなイベント向け)
+pub struct Coalescing<K, T> {
+  // 送られた順に並ぶ。キーは重複しない
+  queue: Mutex<VecDeque<(K, T)>>,
+  item_ready: Condvar,
+}
+
+impl<K: Eq, T> Coalescing<K, T> {
+  pub fn new() -> Self {
+    Self {
+      queue: Mutex::new(VecDeque::new()),
+      item_ready: Condvar::new(),
+    }
+  }
+
+  // 置き換えた場合は古い値を返す。順番は最初に送られたときの位置のまま
+  pub fn send(&self, key: K, value: T) -> Option<T> {
+    let mut queue = self.queue.lock();
+    if let Some((_, old)) = queue.iter_mut().find(|(k, _)| *k == key) {
+      return Some(std::mem::replace(old, value));
+    }
+    queue.push_back((key, value));
+    self.item_ready.notify_one();
+    None
+  }
+
+  pub fn recv(&self) -> T {
+    let mut queue = self.queue.lock();
+    loop {
+      if let Some((_, value)) = queue.pop_front() {
+        return value;
+      }
+      queue = self.item_ready.wait(queue);
+    }
+  }
+
+  pub fn try_recv(&self) -> Option<T> {
+    self.queue.lock().pop_front().map(|(_, value)| value)
+  }
+}
+
+impl<K: Eq, T> Default for Coalescing<K, T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn keeps_only_latest_per_key() {
+    let ch = Coalescing::new();
+    assert_eq!(ch.send("redraw", 1), None);
+    assert_eq!(ch.send("resize", 10), None);
+    assert_eq!(ch.send("redraw", 2), Some(1));
+    assert_eq!(ch.send("redraw", 3), Some(2));
+
+    assert_eq!(ch.recv(), 3);
+    assert_eq!(ch.recv(), 10);
+    assert_eq!(ch.try_recv(), None);
+  }
+
+  #[test]
+  fn recv_waits_for_send() {
+    let ch = Coalescing::new();
+    thread::scope(|s| {
+      s.spawn(|| {
+        thread::sleep(std::time::Duration::from_millis(50));
+        ch.send((), 'x');
+      });
+      assert_eq!(ch.recv(), 'x');
+    });
+  }
+}
@@ -1,11 +1,37 @@
 use std::marker::PhantomData;
+use std::ptr;
 use std::{cell::UnsafeCell, mem::MaybeUninit, sync::atomic::AtomicBool, thread::Thread};
-use std::sync::atomic::Ordering::{Release, Relaxed, Acquire};
+use std::sync::atomic::{AtomicPtr, AtomicU32};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, SeqCst};
 use std::thread;
+use std::time::Instant;
+
+use atomic_wait::{wait, wake_all};
+
+pub mod async_channel;
+mod coalescing;
+mod mailbox;
+mod rendezvous;
+#[cfg(feature = "stress")]
+mod stress;
+
+pub use coalescing::Coalescing;
+pub use mailbox::Mailbox;
+pub use rendezvous::Rendezvous;
+#[cfg(feature = "stress")]
+pub use stress::stress_channel;
+
 pub struct Channel<T> {
   // maybeuniitはoptionのunsafe版
   message: UnsafeCell<MaybeUninit<T>>,
-  ready: AtomicBool,
+  // 0 = 空, 1 = メッセージあり, 2 = 送らずにSenderがdropされた。futexで待てるようにAtomicU32にしている
+  ready: AtomicU32,
+  // receiveで待っている側を起こすためのParker。Box<Box<dyn Parker>>の所有権はswapで受け渡す
+  parker: AtomicPtr<Box<dyn Parker>>,
+  // sendせずにSenderがdropされた
+  sender_dropped: AtomicBool,
+  // Sender::cancelで明示的に送信を取りやめた。sender_droppedより先に立てる
+  cancelled: AtomicBool,
 }
 
 unsafe impl <T> Sync for Channel<T> where T: Send {}
@@ -14,43 +40,161 @@ impl<T> Channel<T> {
   pub const fn new() -> Self {
     Channel {
       message: UnsafeCell::new(MaybeUninit::uninit()),
-      ready: AtomicBool::new(false),
+      ready: AtomicU32::new(0),
+      parker: AtomicPtr::new(ptr::null_mut()),
+      sender_dropped: AtomicBool::new(false),
+      cancelled: AtomicBool::new(false),
     }
   }
 
   // 同じスコープで一つのチャネルしか使えないことを保証するために、&mut selfを取る
-  pub fn split(&mut self) -> (Sender<T>, Receiver<T>) {
-    //　送信されなかった古いメッセージをdropし、readyをfalseに戻す
-    *self = Self::new();
+  // 受け取られていないメッセージがあるとdebugビルドではpanicする。捨てるつもりならreset_in_placeを先に呼ぶ
+  pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
+    debug_assert!(*self.ready.get_mut() != 1, "split would drop a pending message");
+    self.reset_in_place();
     (Sender {
       channel: self,
-      receiving_thread: thread::current(),
     }, Receiver {
       channel: self,
       _no_send: PhantomData,
     })
   }
+
+  // splitと同じだが、受け取られていないメッセージがあれば何もせずにErrを返す
+  pub fn try_split(&mut self) -> Result<(Sender<'_, T>, Receiver<'_, T>), PendingMessage> {
+    if *self.ready.get_mut() == 1 {
+      return Err(PendingMessage);
+    }
+    Ok(self.split())
+  }
+
+  // 送信されなかった古いメッセージをdropし、readyを0に戻す
+  // 作り直さずに同じUnsafeCellをそのまま使い回す
+  pub fn reset_in_place(&mut self) {
+    if *self.ready.get_mut() == 1 {
+      unsafe { self.message.get_mut().assume_init_drop(); }
+    }
+    *self.ready.get_mut() = 0;
+    *self.sender_dropped.get_mut() = false;
+    *self.cancelled.get_mut() = false;
+    let p = std::mem::replace(self.parker.get_mut(), ptr::null_mut());
+    if !p.is_null() {
+      drop(unsafe { Box::from_raw(p) });
+    }
+  }
+
+  // parkerを起こしてもらう対象として登録する
+  fn register_parker(&self, parker: Box<dyn Parker>) {
+    let new = Box::into_raw(Box::new(parker));
+    let old = self.parker.swap(new, SeqCst);
+    if !old.is_null() {
+      drop(unsafe { Box::from_raw(old) });
+    }
+  }
+
+  fn take_parker(&self) -> Option<Box<dyn Parker>> {
+    let p = self.parker.swap(ptr::null_mut(), SeqCst);
+    if p.is_null() {
+      None
+    } else {
+      Some(*unsafe { Box::from_raw(p) })
+    }
+  }
+}
+
+impl<T> Default for Channel<T> {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl<T> Drop for Channel<T> {
   // get_mutは唯一の参照を持っているときにしか呼び出せないため、排他アクセスの保証がある
+  // 受け取られなかったメッセージは、Channelのメモリが片付けられる前にここで1回だけdropされる
+  // (receiveはreadyを0にしてから読み出すので、受け取った分はここでdropされない)
   fn drop(&mut self) {
-    if *self.ready.get_mut() {
-      unsafe { (*self.message.get()).assume_init_drop(); }
+    if *self.ready.get_mut() == 1 {
+      unsafe { self.message.get_mut().assume_init_drop(); }
+    }
+    let p = *self.parker.get_mut();
+    if !p.is_null() {
+      drop(unsafe { Box::from_raw(p) });
     }
   }
 }
 
 pub struct Sender<'a, T> {
   channel: &'a Channel<T>,
-  receiving_thread: Thread,
 }
 
-impl<'a, T> Sender<'a, T> {
+impl<T> Sender<'_, T> {
   pub fn send(self, value: T) {
     unsafe { (*self.channel.message.get()).write(value); }
-    self.channel.ready.store(true, Release);
-    self.receiving_thread.unpark();
+    // readyのstoreとスレッドの取り出しはSeqCstにして、
+    // receive側の「登録してからreadyを確認」とどちらかが必ず相手を観測するようにする
+    self.channel.ready.store(1, SeqCst);
+    wake_all(&self.channel.ready);
+    if let Some(p) = self.channel.take_parker() {
+      p.unpark();
+    }
+    // 送信済みなのでDropで切断扱いにしない
+    std::mem::forget(self);
+  }
+
+  // 送らないことを明示する。receiveはErr(Cancelled)を返して戻る
+  pub fn cancel(self) {
+    self.channel.cancelled.store(true, SeqCst);
+    // 起こすのはDropに任せる
+    drop(self);
+  }
+}
+
+impl<T> Drop for Sender<'_, T> {
+  fn drop(&mut self) {
+    self.channel.sender_dropped.store(true, SeqCst);
+    // sendしたSenderはforgetされるので、ここでは必ず0。futexの値を変えてwait_readyに切断を伝える
+    self.channel.ready.store(2, SeqCst);
+    wake_all(&self.channel.ready);
+    if let Some(p) = self.channel.take_parker() {
+      p.unpark();
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+  // 期限までにメッセージが届かなかった
+  Timeout,
+  // メッセージを送らずにSenderがdropされた
+  Disconnected,
+  // Sender::cancelが呼ばれた
+  Cancelled,
+}
+
+// Sender::cancelで送信が取りやめられた
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+// try_splitしようとしたチャネルに、受け取られていないメッセージが残っている
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingMessage;
+
+// receive_withで待つ方法を差し替える。green threadのランタイムなどで、thread::parkの代わりに使う
+// 1つを受信側が持ってparkし、cloneしたものをSenderが取り出してunparkする
+pub trait Parker: Send + 'static {
+  // unparkされるまで(spuriousに戻ってもよい)受信側を止める
+  fn park(&self);
+  fn unpark(&self);
+}
+
+// receiveで使う。parkはselfではなく呼んだスレッドを止めるので、thread::current()を渡す
+impl Parker for Thread {
+  fn park(&self) {
+    thread::park();
+  }
+
+  fn unpark(&self) {
+    Thread::unpark(self);
   }
 }
 
@@ -60,17 +204,72 @@ pub struct Receiver<'a, T> {
   _no_send: PhantomData<*const ()>,
 }
 
-impl<'a, T> Receiver<'a, T> {
+impl<T> Receiver<'_, T> {
   pub fn is_ready(&self) -> bool {
-    self.channel.ready.load(Relaxed)
+    self.channel.ready.load(Relaxed) == 1
   }
 
-  pub fn receive(self)-> T {
-    // sender以外のunparkでスレッドが起きることを防ぐためのループ
-    if !self.channel.ready.swap(false, Acquire) {
-      thread::park();
+  // thread::parkを使わず、readyのfutexでメッセージを待つ
+  // Senderが送らずにdropされた場合も戻るので、戻った後はis_readyで確認する
+  // Senderのdropもreadyを書き換えてから起こすので、確認してからwaitするまでの間にdropされても取りこぼさない
+  pub fn wait_ready(&self) {
+    while self.channel.ready.load(Acquire) == 0 {
+      wait(&self.channel.ready, 0);
     }
-    unsafe { (*self.channel.message.get()).assume_init_read() }
+  }
+
+  // cancelされずにSenderがdropされた場合は戻らない
+  pub fn receive(self)-> Result<T, Cancelled> {
+    self.receive_with(thread::current())
+  }
+
+  // receiveと同じだが、thread::parkの代わりにparkerで待つ
+  pub fn receive_with<P: Parker + Clone>(self, parker: P) -> Result<T, Cancelled> {
+    // sender以外のunparkで起きることを防ぐためのループ
+    let result = loop {
+      if self.channel.ready.swap(0, Acquire) == 1 {
+        break Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+      }
+      if self.channel.cancelled.load(Acquire) {
+        break Err(Cancelled);
+      }
+      self.channel.register_parker(Box::new(parker.clone()));
+      // 登録する前にsendされていたら、senderは誰も起こしていない
+      if self.channel.ready.load(SeqCst) == 1 || self.channel.cancelled.load(SeqCst) {
+        continue;
+      }
+      parker.park();
+    };
+    // senderに取られていなければ自分で片付ける
+    drop(self.channel.take_parker());
+    result
+  }
+
+  // メッセージが届く、Senderがdropされる、deadlineを過ぎる、のどれかまでブロックする
+  pub fn recv_deadline(self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+    let result = loop {
+      if self.channel.ready.swap(0, Acquire) == 1 {
+        break Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+      }
+      if self.channel.sender_dropped.load(Acquire) {
+        if self.channel.cancelled.load(Relaxed) {
+          break Err(RecvTimeoutError::Cancelled);
+        }
+        break Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        break Err(RecvTimeoutError::Timeout);
+      }
+      self.channel.register_parker(Box::new(thread::current()));
+      if self.channel.ready.load(SeqCst) == 1 || self.channel.sender_dropped.load(SeqCst) {
+        continue;
+      }
+      // spurious wakeupがあるので、起きたら残り時間を計算し直す
+      thread::park_timeout(deadline - now);
+    };
+    drop(self.channel.take_parker());
+    result
   }
 }
 
@@ -93,8 +292,241 @@ mod tests {
           sender.send(42);
           t.unpark();
         });
-        assert_eq!(receiver.receive(), 42);
+        assert_eq!(receiver.receive().unwrap(), 42);
+      });
+
+    }
+
+    #[test]
+    fn receive_on_other_thread() {
+      let mut channel = Channel::new();
+      // チャネルを作ったスレッドとは別のスレッドでsplitしてreceiveし、さらに別のスレッドから送る
+      thread::scope(|s| {
+        let r = s.spawn(|| {
+          let (sender, receiver) = channel.split();
+          s.spawn(move || {
+            // receiverがparkするのを待ってから送る
+            thread::sleep(std::time::Duration::from_millis(100));
+            sender.send(42);
+          });
+          receiver.receive().unwrap()
+        });
+        assert_eq!(r.join().unwrap(), 42);
+      });
+    }
+
+    #[test]
+    fn reset_in_place_reuses_channel() {
+      use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+      static DROPS: AtomicUsize = AtomicUsize::new(0);
+      struct DetectDrop(u32);
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      let mut channel = Channel::new();
+      let (sender, _receiver) = channel.split();
+      sender.send(DetectDrop(1));
+      // 受け取られなかったメッセージはリセットでdropされる
+      channel.reset_in_place();
+      assert_eq!(DROPS.load(Relaxed), 1);
+
+      let (sender, receiver) = channel.split();
+      sender.send(DetectDrop(2));
+      assert_eq!(receiver.receive().unwrap().0, 2);
+      assert_eq!(DROPS.load(Relaxed), 2);
+      channel.reset_in_place();
+      assert_eq!(DROPS.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn pending_message_dropped_with_channel() {
+      use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+      static DROPS: AtomicUsize = AtomicUsize::new(0);
+      struct DetectDrop;
+      impl Drop for DetectDrop {
+        fn drop(&mut self) {
+          DROPS.fetch_add(1, Relaxed);
+        }
+      }
+
+      // 送ったが受け取らずにChannelをdrop
+      let mut channel = Channel::new();
+      // Receiverはすぐにdropされる
+      let (sender, _) = channel.split();
+      sender.send(DetectDrop);
+      assert_eq!(DROPS.load(Relaxed), 0);
+      drop(channel);
+      assert_eq!(DROPS.load(Relaxed), 1);
+
+      // 送らずに両方の端をdropしてからChannelをdrop
+      let mut channel = Channel::<DetectDrop>::new();
+      let (sender, _) = channel.split();
+      drop(sender);
+      drop(channel);
+      assert_eq!(DROPS.load(Relaxed), 1);
+
+      // 受け取った分はChannelのdropで二重にdropされない
+      let mut channel = Channel::new();
+      let (sender, receiver) = channel.split();
+      sender.send(DetectDrop);
+      drop(receiver.receive().unwrap());
+      assert_eq!(DROPS.load(Relaxed), 2);
+      drop(channel);
+      assert_eq!(DROPS.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn receive_with_custom_parker() {
+      use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+      use std::sync::{Arc, Condvar, Mutex};
+      use std::time::Duration;
+
+      // thread::parkを使わず、Mutex+Condvarのフラグで待つ
+      #[derive(Clone, Default)]
+      struct Recording(Arc<(Mutex<bool>, Condvar, AtomicUsize, AtomicUsize)>);
+      impl Parker for Recording {
+        fn park(&self) {
+          let (flag, cv, parks, _) = &*self.0;
+          parks.fetch_add(1, Relaxed);
+          let mut f = flag.lock().unwrap();
+          while !*f {
+            f = cv.wait(f).unwrap();
+          }
+          *f = false;
+        }
+        fn unpark(&self) {
+          let (flag, cv, _, unparks) = &*self.0;
+          unparks.fetch_add(1, Relaxed);
+          *flag.lock().unwrap() = true;
+          cv.notify_one();
+        }
+      }
+
+      let parker = Recording::default();
+      let mut channel = Channel::new();
+      thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+          // receiverがparkするのを待ってから送る
+          thread::sleep(Duration::from_millis(50));
+          sender.send(42);
+        });
+        assert_eq!(receiver.receive_with(parker.clone()), Ok(42));
+      });
+      let (_, _, parks, unparks) = &*parker.0;
+      assert_eq!(parks.load(Relaxed), 1);
+      assert_eq!(unparks.load(Relaxed), 1);
+
+      // 先に送られていればparkしない
+      let (sender, receiver) = channel.split();
+      sender.send(7);
+      assert_eq!(receiver.receive_with(parker.clone()), Ok(7));
+      assert_eq!(parks.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn try_split_keeps_pending_message() {
+      let mut channel = Channel::new();
+      let (sender, _) = channel.split();
+      sender.send(String::from("kept"));
+      assert!(matches!(channel.try_split(), Err(PendingMessage)));
+
+      // メッセージは捨てられていない
+      assert_eq!(*channel.ready.get_mut(), 1);
+      channel.reset_in_place();
+      let (sender, receiver) = channel.try_split().unwrap();
+      sender.send(String::from("next"));
+      assert_eq!(receiver.receive().unwrap(), "next");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "pending message")]
+    fn split_with_pending_message_panics() {
+      let mut channel = Channel::new();
+      let (sender, _) = channel.split();
+      sender.send(1);
+      channel.split();
+    }
+
+    #[test]
+    fn wait_ready_on_futex() {
+      let mut channel = Channel::new();
+      thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+          thread::sleep(std::time::Duration::from_millis(50));
+          sender.send(7);
+        });
+        assert!(!receiver.is_ready());
+        receiver.wait_ready();
+        assert!(receiver.is_ready());
+        assert_eq!(receiver.receive().unwrap(), 7);
+      });
+
+      let (sender, receiver) = channel.split();
+      drop(sender);
+      // 切断されていればブロックしない
+      receiver.wait_ready();
+      assert!(!receiver.is_ready());
+    }
+
+    #[test]
+    fn recv_deadline_outcomes() {
+      use std::time::Duration;
+
+      let mut channel = Channel::new();
+      thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+          thread::sleep(Duration::from_millis(50));
+          sender.send(1);
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert_eq!(receiver.recv_deadline(deadline), Ok(1));
+      });
+
+      let (sender, receiver) = channel.split();
+      let start = Instant::now();
+      let deadline = start + Duration::from_millis(50);
+      assert_eq!(receiver.recv_deadline(deadline), Err(RecvTimeoutError::Timeout));
+      assert!(start.elapsed() >= Duration::from_millis(50));
+      drop(sender);
+
+      thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+          thread::sleep(Duration::from_millis(50));
+          drop(sender);
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert_eq!(receiver.recv_deadline(deadline), Err(RecvTimeoutError::Disconnected));
+      });
+    }
+
+    #[test]
+    fn cancel_wakes_receiver() {
+      use std::time::Duration;
+
+      let mut channel = Channel::<i32>::new();
+      thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+          // receiverがparkするのを待ってからcancelする
+          thread::sleep(Duration::from_millis(50));
+          sender.cancel();
+        });
+        assert_eq!(receiver.receive(), Err(Cancelled));
       });
 
+      let (sender, receiver) = channel.split();
+      sender.cancel();
+      let deadline = Instant::now() + Duration::from_secs(10);
+      assert_eq!(receiver.recv_deadline(deadline), Err(RecvTimeoutError::Cancelled));
     }
 }
@@ -0,0 +1,102 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
+
+// 最新の値を1つだけ持つロックフリーの箱。"最新のセンサー値"のように古い値がいらない用途向け
+// Box<T>の所有権はswapで受け渡す
+pub struct Mailbox<T> {
+  slot: AtomicPtr<T>,
+  _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+  pub const fn new() -> Self {
+    Self {
+      slot: AtomicPtr::new(ptr::null_mut()),
+      _marker: PhantomData,
+    }
+  }
+
+  // 受け取られていない古い値はここでdropする
+  pub fn put(&self, value: Box<T>) {
+    let old = self.slot.swap(Box::into_raw(value), AcqRel);
+    if !old.is_null() {
+      drop(unsafe { Box::from_raw(old) });
+    }
+  }
+
+  pub fn take(&self) -> Option<Box<T>> {
+    let p = self.slot.swap(ptr::null_mut(), Acquire);
+    if p.is_null() {
+      None
+    } else {
+      Some(unsafe { Box::from_raw(p) })
+    }
+  }
+}
+
+impl<T> Default for Mailbox<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> Drop for Mailbox<T> {
+  fn drop(&mut self) {
+    let p = *self.slot.get_mut();
+    if !p.is_null() {
+      drop(unsafe { Box::from_raw(p) });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering::Relaxed;
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn concurrent_put_take_drops_everything() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Reading(usize);
+    impl Drop for Reading {
+      fn drop(&mut self) {
+        DROPS.fetch_add(1, Relaxed);
+      }
+    }
+
+    let mailbox = Mailbox::new();
+    assert!(mailbox.take().is_none());
+    let taken = thread::scope(|s| {
+      for id in 0..3 {
+        let mailbox = &mailbox;
+        s.spawn(move || {
+          for i in 0..100 {
+            mailbox.put(Box::new(Reading(id * 100 + i)));
+          }
+        });
+      }
+      let mut taken = 0;
+      for _ in 0..100 {
+        if let Some(r) = mailbox.take() {
+          assert!(r.0 < 300);
+          taken += 1;
+        }
+        thread::yield_now();
+      }
+      taken
+    });
+    let last = mailbox.take();
+    assert!(taken > 0 || last.is_some());
+    drop(last);
+    drop(mailbox);
+    // 上書きされた値も、受け取った値も、残っていた値もすべてdropされている
+    assert_eq!(DROPS.load(Relaxed), 300);
+  }
+}
@@ -0,0 +1,113 @@
+use condvar::Condvar;
+use mutex::Mutex;
+
+struct State<T> {
+  slot: Option<T>,
+  // 渡した数と受け取られた数。senderは自分の番号が受け取られるまで待つ
+  sent: u64,
+  received: u64,
+}
+
+// 容量0のチャネル。sendはreceiverが値を受け取るまで戻らない
+pub struct Rendezvous<T> {
+  state: Mutex<State<T>>,
+  // slotに値が入った
+  item_ready: Condvar,
+  // slotの値が受け取られた
+  item_taken: Condvar,
+}
+
+impl<T> Rendezvous<T> {
+  pub fn new() -> Self {
+    Self {
+      state: Mutex::new(State { slot: None, sent: 0, received: 0 }),
+      item_ready: Condvar::new(),
+      item_taken: Condvar::new(),
+    }
+  }
+
+  pub fn send(&self, value: T) {
+    let mut state = self.state.lock();
+    // 他のsenderの値が受け取られるのを待つ
+    while state.slot.is_some() {
+      state = self.item_taken.wait(state);
+    }
+    state.slot = Some(value);
+    state.sent += 1;
+    let ticket = state.sent;
+    self.item_ready.notify_one();
+    while state.received < ticket {
+      state = self.item_taken.wait(state);
+    }
+  }
+
+  // receiverは1つだけを想定している
+  pub fn recv(&self) -> T {
+    let mut state = self.state.lock();
+    let value = loop {
+      if let Some(value) = state.slot.take() {
+        break value;
+      }
+      state = self.item_ready.wait(state);
+    };
+    state.received += 1;
+    // 渡し終わったsenderと、slotが空くのを待っているsenderの両方を起こす
+    self.item_taken.notify_all();
+    value
+  }
+}
+
+impl<T> Default for Rendezvous<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicBool;
+  use std::sync::atomic::Ordering::{Acquire, Release};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use super::*;
+
+  #[test]
+  fn send_waits_for_recv() {
+    let ch = Rendezvous::new();
+    let receiving = AtomicBool::new(false);
+    thread::scope(|s| {
+      s.spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        receiving.store(true, Release);
+        assert_eq!(ch.recv(), 1);
+      });
+      let start = Instant::now();
+      ch.send(1);
+      // recvが呼ばれる前にsendは戻らない
+      assert!(receiving.load(Acquire));
+      assert!(start.elapsed() >= Duration::from_millis(100));
+    });
+  }
+
+  #[test]
+  fn multiple_senders() {
+    let ch = Rendezvous::new();
+    thread::scope(|s| {
+      for id in 0..3 {
+        let ch = &ch;
+        s.spawn(move || {
+          for i in 0..10 {
+            ch.send((id, i));
+          }
+        });
+      }
+      let mut next = [0; 3];
+      for _ in 0..30 {
+        let (id, i) = ch.recv();
+        assert_eq!(i, next[id]);
+        next[id] += 1;
+      }
+    });
+  }
+}
@@ -0,0 +1,62 @@
+use std::thread;
+
+use xorshift::{jitter, XorShift};
+
+use crate::{Channel, Rendezvous};
+
+// one-shotのChannelを使い回しながら別スレッドから送り、
+// Rendezvousには複数のsenderから送って、メッセージの取りこぼしや順序の入れ替わりを調べる
+pub fn stress_channel(threads: usize, iters: usize) -> Result<(), String> {
+  let mut channel = Channel::new();
+  let mut rng = XorShift::new();
+  for i in 0..iters {
+    let (sender, receiver) = channel.split();
+    let delay = rng.next_u64();
+    let got = thread::scope(|s| {
+      s.spawn(move || {
+        let mut rng = XorShift::with_seed(delay);
+        jitter(&mut rng);
+        sender.send(i);
+      });
+      receiver.receive().unwrap()
+    });
+    if got != i {
+      return Err(format!("one-shot round {i} received {got}"));
+    }
+  }
+
+  let ch = Rendezvous::new();
+  let mut result = Ok(());
+  thread::scope(|s| {
+    for id in 0..threads {
+      let ch = &ch;
+      s.spawn(move || {
+        let mut rng = XorShift::new();
+        for seq in 0..iters {
+          jitter(&mut rng);
+          ch.send((id, seq));
+        }
+      });
+    }
+    // 途中で抜けるとsenderがsendで止まったままscopeが終わらないので、最後まで受け取ってから返す
+    let mut next = vec![0; threads];
+    for _ in 0..threads * iters {
+      let (id, seq) = ch.recv();
+      if seq != next[id] && result.is_ok() {
+        result = Err(format!("sender {id}: expected message {}, got {seq}", next[id]));
+      }
+      next[id] = seq + 1;
+    }
+  });
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn smoke() {
+    assert_eq!(stress_channel(3, 100), Ok(()));
+  }
+}
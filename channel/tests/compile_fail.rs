@@ -0,0 +1,6 @@
+// Send/Syncにならないはずの型が誤って実装されていないことや、同時に使えないはずのAPIが使えないことを確かめる
+#[test]
+fn compile_fail() {
+  let t = trybuild::TestCases::new();
+  t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,9 @@
+use channel::async_channel::bounded;
+
+fn main() {
+  let (_tx, mut rx) = bounded::<i32>(1);
+  // recv_waiterは1つなので、recvを同時に2つ待たせることはできない
+  let a = rx.recv();
+  let b = rx.recv();
+  drop((a, b));
+}
@@ -0,0 +1,10 @@
+use std::rc::Rc;
+
+use channel::Channel;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+  // Rcは他スレッドに送れないので、Channel<Rc<_>>は共有できない
+  assert_sync::<Channel<Rc<i32>>>();
+}
@@ -0,0 +1,8 @@
+use channel::Receiver;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+  // ReceiverはPhantomData<*const ()>を持つので、T: Sendでも他スレッドに渡せない
+  assert_send::<Receiver<'static, i32>>();
+}
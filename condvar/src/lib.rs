@@ -1,5 +1,5 @@
 use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::sync::atomic::Ordering::Relaxed;
 use mutex::{MutexGuard};
 
 use atomic_wait::{wait, wake_all, wake_one};
@@ -7,6 +7,41 @@ use atomic_wait::{wait, wake_all, wake_one};
 pub struct Condvar {
 	counter: AtomicU32,
 	num_waiters: AtomicU32,
+	// wait_sequentialのwaiterはcounterとは別のこのワードで寝る。waitのwaiterを鎖に巻き込まない
+	sequence: AtomicU32,
+	seq_waiters: AtomicU32,
+	// notify_all_sequentialで、あと何回次のwaiterを起こすか
+	chain: AtomicU32,
+}
+
+// waitしている間だけnum_waitersを1つ数える。unwindで抜けてもdropで必ず戻す
+struct Waiting<'a>(&'a AtomicU32);
+
+impl<'a> Waiting<'a> {
+	fn new(num_waiters: &'a AtomicU32) -> Self {
+		num_waiters.fetch_add(1, Relaxed);
+		Self(num_waiters)
+	}
+}
+
+impl Drop for Waiting<'_> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Relaxed);
+	}
+}
+
+// wait_sequentialで一度でも起こされていれば、dropで次のwaiterを起こす。panicで抜けても鎖が切れない
+struct PassChain<'a> {
+	condvar: &'a Condvar,
+	woken: bool,
+}
+
+impl Drop for PassChain<'_> {
+	fn drop(&mut self) {
+		if self.woken {
+			self.condvar.pass_chain();
+		}
+	}
 }
 
 impl Condvar {
@@ -14,13 +49,22 @@ impl Condvar {
 		Self {
 			counter: AtomicU32::new(0),
 			num_waiters: AtomicU32::new(0),
+			sequence: AtomicU32::new(0),
+			seq_waiters: AtomicU32::new(0),
+			chain: AtomicU32::new(0),
 		}
 	}
 
+	// waiterはmutexを持ったままnum_waitersを増やす。notifyする側が条件を書き換えたときと同じmutexを
+	// 取っていれば(今持っているか、一度取って離した後なら)、mutexの順序でその増加が見えるのでRelaxedでよい
+	// mutexを一度も取らずにnotifyした場合の取りこぼしは呼び出し側の責任
 	pub fn notify_one(&self) {
 		if self.num_waiters.load(Relaxed) > 0 {
 			self.counter.fetch_add(1, Relaxed);
 			wake_one(&self.counter);
+		} else if self.seq_waiters.load(Relaxed) > 0 {
+			self.sequence.fetch_add(1, Relaxed);
+			wake_one(&self.sequence);
 		}
 	}
 
@@ -29,19 +73,114 @@ impl Condvar {
 			self.counter.fetch_add( 1, Relaxed);
 			wake_all(&self.counter);
 		}
+		if self.seq_waiters.load(Relaxed) > 0 {
+			self.sequence.fetch_add(1, Relaxed);
+			wake_all(&self.sequence);
+		}
+	}
+
+	// notify_allと同じく今待っている全員を起こすが、wait_sequentialのwaiterは一度に1つだけ起こす
+	// 起きたwaiterがmutexを取り直して離した後に次を起こすので、mutexの取り合いが起きない
+	// waitのwaiterはnotify_allと同じくまとめて起こす
+	pub fn notify_all_sequential(&self) {
+		if self.num_waiters.load(Relaxed) > 0 {
+			self.counter.fetch_add(1, Relaxed);
+			wake_all(&self.counter);
+		}
+		let n = self.seq_waiters.load(Relaxed);
+		if n > 0 {
+			// 前のnotifyの鎖が残っていれば、多い方に合わせる。上書きで減らすと、前のnotifyで起こすはずの
+			// waiterが順番をもらえない。足りなくても、最後のwake_allで残りがまとめて起きる
+			self.chain.fetch_max(n - 1, Relaxed);
+			self.sequence.fetch_add(1, Relaxed);
+			wake_one(&self.sequence);
+		}
+	}
+
+	// 途中で後から来たwaiterが順番を使ってしまっても取りこぼさないように、最後はwake_allにする
+	fn pass_chain(&self) {
+		let Ok(left) = self.chain.fetch_update(Relaxed, Relaxed, |c| c.checked_sub(1)) else {
+			return;
+		};
+		self.sequence.fetch_add(1, Relaxed);
+		if left == 1 {
+			wake_all(&self.sequence);
+		} else {
+			wake_one(&self.sequence);
+		}
+	}
+
+	// 診断用。Relaxedで読むだけなので、戻った直後にはもう変わっているかもしれない
+	pub fn has_waiters(&self) -> bool {
+		self.waiter_count() > 0
+	}
+
+	pub fn waiter_count(&self) -> u32 {
+		self.num_waiters.load(Relaxed) + self.seq_waiters.load(Relaxed)
 	}
 
 	pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
-		self.num_waiters.fetch_add(1, Relaxed);
+		let waiting = Waiting::new(&self.num_waiters);
 
 		let counter_value = self.counter.load(Relaxed);
 		let mutex = guard.mutex;
 		drop(guard);
 		wait( &self.counter, counter_value);
 
-		self.num_waiters.fetch_sub(1, Relaxed);
 		// lock again
-		mutex.lock()
+		let guard = mutex.lock();
+		drop(waiting);
+		guard
+	}
+
+	// waitと同じようにguardを解放して待つが、起きた後はreacquireで好きなロックを取り直す
+	pub fn wait_then<T, G>(&self, guard: MutexGuard<'_, T>, reacquire: impl FnOnce() -> G) -> G {
+		let waiting = Waiting::new(&self.num_waiters);
+
+		let counter_value = self.counter.load(Relaxed);
+		drop(guard);
+		wait(&self.counter, counter_value);
+
+		// 取り直すまでは数えたままにする。reacquireがpanicしたら、unwindでwaitingがdropされて数から外れる
+		let guard = reacquire();
+		drop(waiting);
+		guard
+	}
+
+	// notify_all_sequentialで1つずつ起こされる待ち方。conditionがtrueの間待ち、falseになったら
+	// mutexを持ったままfを呼ぶ。fが戻ってmutexを離してから、次のwaiterを起こす
+	pub fn wait_sequential<T, R>(
+		&self,
+		guard: MutexGuard<'_, T>,
+		mut condition: impl FnMut(&mut T) -> bool,
+		f: impl FnOnce(&mut T) -> R,
+	) -> R {
+		// guardより先に宣言して、panicしたときもmutexを離してからdropされるようにする
+		let mut pass = PassChain { condvar: self, woken: false };
+		let mut guard = guard;
+		let mutex = guard.mutex;
+		while condition(&mut guard) {
+			let waiting = Waiting::new(&self.seq_waiters);
+			let sequence = self.sequence.load(Relaxed);
+			drop(guard);
+			// 起こされたのに条件が満たされていなかった。mutexを離したので順番を次に回す
+			if std::mem::take(&mut pass.woken) {
+				self.pass_chain();
+			}
+			wait(&self.sequence, sequence);
+			guard = mutex.lock();
+			drop(waiting);
+			pass.woken = true;
+		}
+		let r = f(&mut guard);
+		drop(guard);
+		r
+	}
+}
+
+impl Default for Condvar {
+	fn default() -> Self {
+		Self::new()
 	}
 }
 
@@ -74,4 +213,174 @@ mod tests {
 		});
 			assert!(wakeups < 10);
     }
+
+    #[test]
+    fn wait_then_reacquires_with_closure() {
+			let mutex = mutex::Mutex::new(0);
+			let condvar = Condvar::new();
+
+			thread::scope(|s| {
+				s.spawn(|| {
+					while !condvar.has_waiters() {
+						thread::yield_now();
+					}
+					*mutex.lock() = 7;
+					condvar.notify_one();
+				});
+
+				let mut m = mutex.lock();
+				while *m == 0 {
+					// 起きた後は新しいガードを取り直す
+					m = condvar.wait_then(m, || mutex.lock());
+				}
+				assert_eq!(*m, 7);
+			});
+
+			// ガードを取り直さずに値だけ読むこともできる
+			thread::scope(|s| {
+				s.spawn(|| {
+					while !condvar.has_waiters() {
+						thread::yield_now();
+					}
+					*mutex.lock() = 8;
+					condvar.notify_one();
+				});
+				let v = condvar.wait_then(mutex.lock(), || *mutex.lock());
+				assert!(v == 7 || v == 8);
+			});
+    }
+
+    #[test]
+    fn notify_all_sequential_wakes_one_at_a_time() {
+			const WAITERS: u32 = 8;
+			// (条件, 各waiterがmutexを持っている間に見た残りの順番の数)
+			let mutex = mutex::Mutex::new((false, Vec::new()));
+			let condvar = Condvar::new();
+
+			thread::scope(|s| {
+				for _ in 0..WAITERS {
+					s.spawn(|| {
+						condvar.wait_sequential(mutex.lock(), |(ready, _)| !*ready, |(_, seen)| {
+							seen.push(condvar.chain.load(Relaxed));
+						});
+					});
+				}
+				while condvar.waiter_count() < WAITERS {
+					thread::yield_now();
+				}
+				mutex.lock().0 = true;
+				condvar.notify_all_sequential();
+			});
+			assert_eq!(condvar.waiter_count(), 0);
+			// 順番はmutexを離してから渡すので、k番目に入ったwaiterが見る前に渡された順番は高々k個
+			// mutexを持ったまま渡すと、自分の分がもう減っている
+			let seen = &mutex.lock().1;
+			assert_eq!(seen.len(), WAITERS as usize);
+			for (k, &left) in seen.iter().enumerate() {
+				assert!(left + k as u32 >= WAITERS - 1, "{seen:?}");
+			}
+    }
+
+    #[test]
+    fn sequential_chain_survives_panic() {
+			let mutex = mutex::Mutex::new(false);
+			let condvar = Condvar::new();
+			let panicked = std::sync::atomic::AtomicBool::new(false);
+
+			thread::scope(|s| {
+				let handles: Vec<_> = (0..4)
+					.map(|_| {
+						s.spawn(|| {
+							condvar.wait_sequential(mutex.lock(), |ready| !*ready, |_| {
+								// 最初に起きたwaiterだけがmutexを持ったままpanicする
+								if !panicked.swap(true, Relaxed) {
+									std::panic::panic_any("first");
+								}
+							})
+						})
+					})
+					.collect();
+				while condvar.waiter_count() < 4 {
+					thread::yield_now();
+				}
+				*mutex.lock() = true;
+				condvar.notify_all_sequential();
+				// 鎖が切れると残りのwaiterが起きず、joinが返らない
+				let failed = handles.into_iter().map(|h| h.join()).filter(Result::is_err).count();
+				assert_eq!(failed, 1);
+			});
+			assert_eq!(condvar.waiter_count(), 0);
+    }
+
+    #[test]
+    fn notify_under_mutex_is_not_lost() {
+			let mutex = mutex::Mutex::new(0u32);
+			let condvar = Condvar::new();
+
+			// waiterがwaitに入る直前にnotifyされる状況を何度も作る。取りこぼすとscopeが終わらない
+			for i in 1..=2000 {
+				thread::scope(|s| {
+					s.spawn(|| {
+						let mut m = mutex.lock();
+						while *m != i {
+							m = condvar.wait(m);
+						}
+					});
+					s.spawn(|| {
+						let mut m = mutex.lock();
+						*m = i;
+						condvar.notify_one();
+						drop(m);
+					});
+				});
+			}
+			assert_eq!(condvar.waiter_count(), 0);
+    }
+
+    #[test]
+    fn observe_waiters() {
+			let mutex = mutex::Mutex::new(false);
+			let condvar = Condvar::new();
+			assert!(!condvar.has_waiters());
+
+			thread::scope(|s| {
+				s.spawn(|| {
+					let mut ready = mutex.lock();
+					while !*ready {
+						ready = condvar.wait(ready);
+					}
+				});
+
+				while !condvar.has_waiters() {
+					thread::yield_now();
+				}
+				assert_eq!(condvar.waiter_count(), 1);
+				*mutex.lock() = true;
+				condvar.notify_one();
+			});
+			assert_eq!(condvar.waiter_count(), 0);
+    }
+
+    #[test]
+    fn waiter_count_recovers_after_panic() {
+			let mutex = mutex::Mutex::new(false);
+			let condvar = Condvar::new();
+
+			thread::scope(|s| {
+				let waiter = s.spawn(|| {
+					// waitingが生きている間にpanicさせる
+				condvar.wait_then(mutex.lock(), || std::panic::panic_any("payload"))
+				});
+				while !condvar.has_waiters() {
+					thread::yield_now();
+				}
+				*mutex.lock() = true;
+				condvar.notify_one();
+				let payload = waiter.join().unwrap_err();
+				assert_eq!(payload.downcast_ref::<&str>(), Some(&"payload"));
+			});
+			assert_eq!(condvar.waiter_count(), 0);
+			// mutexは解放されたまま
+			assert!(*mutex.lock());
+    }
 }
@@ -0,0 +1,100 @@
+use std::ops::DerefMut;
+
+use mutex::{Mutex, MutexGuard};
+use rwlock::{ReadGuard, RwLock, WriteGuard};
+use spin_lock::{Guard, RetryPolicy, SpinLock};
+
+pub mod shared;
+
+// dropでロックを解放するガード。Targetは守っている値の型
+pub trait LockGuard {
+	type Target: ?Sized;
+}
+
+impl<T, P> LockGuard for Guard<'_, T, P> {
+	type Target = T;
+}
+
+impl<T> LockGuard for MutexGuard<'_, T> {
+	type Target = T;
+}
+
+impl<T, const MAX_READERS: u32> LockGuard for ReadGuard<'_, T, MAX_READERS> {
+	type Target = T;
+}
+
+impl<T, const MAX_READERS: u32> LockGuard for WriteGuard<'_, T, MAX_READERS> {
+	type Target = T;
+}
+
+// Tを守っているロック。種類を問わずacquireで排他的なガードが取れる
+pub trait Lockable<T> {
+	type Guard<'a>: LockGuard<Target = T> + DerefMut<Target = T>
+	where
+		Self: 'a;
+
+	fn acquire(&self) -> Self::Guard<'_>;
+}
+
+impl<T, P: RetryPolicy> Lockable<T> for SpinLock<T, P> {
+	type Guard<'a> = Guard<'a, T, P> where Self: 'a;
+
+	fn acquire(&self) -> Self::Guard<'_> {
+		self.lock()
+	}
+}
+
+impl<T> Lockable<T> for Mutex<T> {
+	type Guard<'a> = MutexGuard<'a, T> where Self: 'a;
+
+	fn acquire(&self) -> Self::Guard<'_> {
+		self.lock()
+	}
+}
+
+// RwLockは書き込みロックを取る
+impl<T, const MAX_READERS: u32> Lockable<T> for RwLock<T, MAX_READERS> {
+	type Guard<'a> = WriteGuard<'a, T, MAX_READERS> where Self: 'a;
+
+	fn acquire(&self) -> Self::Guard<'_> {
+		self.write()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use super::*;
+
+	fn bump<L: Lockable<i32>>(l: &L) {
+		*l.acquire() += 1;
+	}
+
+	fn bump_from_threads<L: Lockable<i32> + Sync>(l: &L) {
+		thread::scope(|s| {
+			for _ in 0..4 {
+				s.spawn(|| {
+					for _ in 0..100 {
+						bump(l);
+					}
+				});
+			}
+		});
+	}
+
+	#[test]
+	fn bump_any_lock() {
+		let spin = SpinLock::new(0);
+		bump_from_threads(&spin);
+		assert_eq!(*spin.lock(), 400);
+
+		let mutex = Mutex::new(0);
+		bump_from_threads(&mutex);
+		assert_eq!(*mutex.lock(), 400);
+
+		let rwlock = RwLock::new(0);
+		bump(&rwlock);
+		assert_eq!(*rwlock.read(), 1);
+	}
+}
@@ -0,0 +1,234 @@
+use channel::{Channel, Receiver, Sender};
+use mutex::Mutex;
+use rwlock::RwLock;
+
+// どう使うかのヒント。buildがそれに合うプリミティブを選ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessHint {
+	// 読み込みが多い。new_reader_preferringで作ったRwLock。writerはreaderが途切れるまで待つ
+	ReadHeavy,
+	// 書き込みが多い。writerを優先するRwLock
+	WriteHeavy,
+	// 毎回排他的に触る。Mutex
+	Exclusive,
+	// 1回渡して1回受け取るだけ。one-shotのChannel
+	OneShot,
+}
+
+// buildが返す共通のインターフェース。中身はあってもなくてもよい
+pub trait Shared<T>: Send + Sync {
+	// 値を入れる。入れられなければ値を返す(OneShotは2回目以降Err)
+	fn put(&self, value: T) -> Result<(), T>;
+	// 値を取り出す。空ならNoneで、ブロックはしない
+	fn take(&self) -> Option<T>;
+	// 値を取り出さずにfに見せる。空ならfを呼ばずにfalse
+	// RwLockはreadで取るので、readerどうしは同時に入れる。OneShotは受け取るまで中を見られないので常にfalse
+	fn read(&self, f: &mut dyn FnMut(&T)) -> bool;
+}
+
+// RwLockはreadで&Tを複数のスレッドに同時に渡すので、T: Syncのときだけ選べる
+pub fn build<T: Send + Sync + 'static>(value: T, hint: AccessHint) -> Box<dyn Shared<T>> {
+	match hint {
+		AccessHint::ReadHeavy => Box::new(RwLock::new_reader_preferring(Some(value))),
+		AccessHint::WriteHeavy => Box::new(RwLock::new(Some(value))),
+		AccessHint::Exclusive | AccessHint::OneShot => build_unsync(value, hint),
+	}
+}
+
+// T: Syncでなくても使えるbuild。共有して読めないので、ReadHeavyとWriteHeavyもMutexにする
+pub fn build_unsync<T: Send + 'static>(value: T, hint: AccessHint) -> Box<dyn Shared<T>> {
+	match hint {
+		AccessHint::ReadHeavy | AccessHint::WriteHeavy | AccessHint::Exclusive => Box::new(Mutex::new(Some(value))),
+		AccessHint::OneShot => {
+			let shot = OneShot::new();
+			shot.put(value).ok().expect("fresh channel accepts a message");
+			Box::new(shot)
+		}
+	}
+}
+
+impl<T: Send + Sync> Shared<T> for RwLock<Option<T>> {
+	fn put(&self, value: T) -> Result<(), T> {
+		*self.write() = Some(value);
+		Ok(())
+	}
+
+	fn take(&self) -> Option<T> {
+		self.write().take()
+	}
+
+	fn read(&self, f: &mut dyn FnMut(&T)) -> bool {
+		self.read().as_ref().map(f).is_some()
+	}
+}
+
+impl<T: Send> Shared<T> for Mutex<Option<T>> {
+	fn put(&self, value: T) -> Result<(), T> {
+		*self.lock() = Some(value);
+		Ok(())
+	}
+
+	fn take(&self) -> Option<T> {
+		self.lock().take()
+	}
+
+	fn read(&self, f: &mut dyn FnMut(&T)) -> bool {
+		self.lock().as_ref().map(f).is_some()
+	}
+}
+
+// Channelと、それを借りている両端を一緒に持つ
+struct OneShot<T: 'static> {
+	// channelより先にdropされるように、両端を先に宣言する
+	sender: Mutex<Option<Sender<'static, T>>>,
+	receiver: Mutex<Option<Receiver<'static, T>>>,
+	// Boxなので、OneShotがムーブしてもアドレスは変わらない
+	channel: *mut Channel<T>,
+}
+
+// Channel<T>とその両端を持っているだけなので、T: Sendなら送れる
+unsafe impl<T: Send> Send for OneShot<T> {}
+unsafe impl<T: Send> Sync for OneShot<T> {}
+
+impl<T> OneShot<T> {
+	fn new() -> Self {
+		let channel = Box::into_raw(Box::new(Channel::new()));
+		// 両端はOneShotの外に出さず、channelを解放する前にdropするので'staticにしてよい
+		let (sender, receiver) = unsafe { (*channel).split() };
+		Self {
+			sender: Mutex::new(Some(sender)),
+			receiver: Mutex::new(Some(receiver)),
+			channel,
+		}
+	}
+}
+
+impl<T> Drop for OneShot<T> {
+	fn drop(&mut self) {
+		self.sender.lock().take();
+		self.receiver.lock().take();
+		drop(unsafe { Box::from_raw(self.channel) });
+	}
+}
+
+impl<T: Send> Shared<T> for OneShot<T> {
+	fn put(&self, value: T) -> Result<(), T> {
+		match self.sender.lock().take() {
+			Some(sender) => {
+				sender.send(value);
+				Ok(())
+			}
+			None => Err(value),
+		}
+	}
+
+	fn take(&self) -> Option<T> {
+		let mut receiver = self.receiver.lock();
+		// receiveは届くまでブロックするので、届いているときだけ呼ぶ
+		if !receiver.as_ref()?.is_ready() {
+			return None;
+		}
+		receiver.take()?.receive().ok()
+	}
+
+	fn read(&self, _f: &mut dyn FnMut(&T)) -> bool {
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use super::*;
+
+	#[test]
+	fn build_each_hint() {
+		for hint in [AccessHint::ReadHeavy, AccessHint::WriteHeavy, AccessHint::Exclusive] {
+			let shared = build(vec![1], hint);
+			thread::scope(|s| {
+				for i in 0..4 {
+					let shared = &shared;
+					s.spawn(move || {
+						let mut v = loop {
+							if let Some(v) = shared.take() {
+								break v;
+							}
+							thread::yield_now();
+						};
+						v.push(i);
+						shared.put(v).unwrap();
+					});
+				}
+			});
+			let mut v = shared.take().unwrap();
+			v.sort();
+			assert_eq!(v, [0, 1, 1, 2, 3], "{hint:?}");
+			assert!(shared.take().is_none());
+		}
+
+		// OneShotは1回だけ受け取れて、入れ直せない
+		let shared = build(String::from("once"), AccessHint::OneShot);
+		let got = thread::scope(|s| s.spawn(|| shared.take()).join().unwrap());
+		assert_eq!(got.as_deref(), Some("once"));
+		assert!(shared.take().is_none());
+		assert_eq!(shared.put(String::from("again")), Err(String::from("again")));
+	}
+
+	#[test]
+	fn rwlock_hints_share_reads() {
+		for hint in [AccessHint::ReadHeavy, AccessHint::WriteHeavy] {
+			let shared = build(7, hint);
+			// 2つのreaderが同時に中にいないとbarrierを越えられない。writeで取っているとここで止まる
+			let barrier = std::sync::Barrier::new(2);
+			thread::scope(|s| {
+				for _ in 0..2 {
+					s.spawn(|| {
+						assert!(shared.read(&mut |v| {
+							assert_eq!(*v, 7);
+							barrier.wait();
+						}));
+					});
+				}
+			});
+			assert_eq!(shared.take(), Some(7), "{hint:?}");
+			assert!(!shared.read(&mut |_| unreachable!()));
+		}
+	}
+
+	#[test]
+	fn build_unsync_accepts_non_sync_values() {
+		use std::cell::Cell;
+
+		for hint in [AccessHint::ReadHeavy, AccessHint::WriteHeavy, AccessHint::Exclusive] {
+			let shared = build_unsync(Cell::new(1), hint);
+			thread::scope(|s| {
+				s.spawn(|| {
+					let mut seen = 0;
+					assert!(shared.read(&mut |c| seen = c.get()));
+					assert_eq!(seen, 1);
+					let c = shared.take().unwrap();
+					c.set(2);
+					shared.put(c).unwrap();
+				});
+			});
+			assert_eq!(shared.take().map(Cell::into_inner), Some(2), "{hint:?}");
+		}
+	}
+
+	#[test]
+	fn one_shot_pending_message_dropped() {
+		use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+		static DROPS: AtomicUsize = AtomicUsize::new(0);
+		struct DetectDrop;
+		impl Drop for DetectDrop {
+			fn drop(&mut self) {
+				DROPS.fetch_add(1, Relaxed);
+			}
+		}
+
+		drop(build(DetectDrop, AccessHint::OneShot));
+		assert_eq!(DROPS.load(Relaxed), 1);
+	}
+}
@@ -0,0 +1,286 @@
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use crate::Mutex;
+
+struct State {
+  locked: bool,
+  // ロックを待っているLockのidとwaker。先頭から順に起こす
+  // 1つのLockにつき高々1つで、取れたときとdropされたときに取り除く
+  waiters: VecDeque<(u64, Waker)>,
+  next_id: u64,
+}
+
+// 競合したらスレッドをブロックせずにwakerを登録してexecutorに戻る
+pub struct AsyncMutex<T> {
+  state: Mutex<State>,
+  value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for AsyncMutex<T> where T: Send {}
+
+impl<T> AsyncMutex<T> {
+  pub fn new(value: T) -> Self {
+    Self {
+      state: Mutex::new(State { locked: false, waiters: VecDeque::new(), next_id: 0 }),
+      value: UnsafeCell::new(value),
+    }
+  }
+
+  pub async fn lock(&self) -> AsyncMutexGuard<'_, T> {
+    Lock { mutex: self, id: None }.await
+  }
+}
+
+struct Lock<'a, T> {
+  mutex: &'a AsyncMutex<T>,
+  // 一度でもPendingを返していれば、waitersに登録したときのid
+  id: Option<u64>,
+}
+
+impl State {
+  fn position(&self, id: u64) -> Option<usize> {
+    self.waiters.iter().position(|(i, _)| *i == id)
+  }
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+  type Output = AsyncMutexGuard<'a, T>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let mutex = self.mutex;
+    let mut state = mutex.state.lock();
+    if !state.locked {
+      state.locked = true;
+      // 起こされる前に取れたなら、自分のエントリが残っている
+      if let Some(i) = self.id.take().and_then(|id| state.position(id)) {
+        state.waiters.remove(i);
+      }
+      return Poll::Ready(AsyncMutexGuard { mutex });
+    }
+    // 何度pollされてもエントリは1つ。残っていればwakerだけ差し替え、起こされて消えていれば並び直す
+    match self.id.and_then(|id| state.position(id)) {
+      Some(i) => {
+        let w = &mut state.waiters[i].1;
+        if !w.will_wake(cx.waker()) {
+          *w = cx.waker().clone();
+        }
+      }
+      None => {
+        let id = *self.id.get_or_insert_with(|| {
+          state.next_id += 1;
+          state.next_id
+        });
+        state.waiters.push_back((id, cx.waker().clone()));
+      }
+    }
+    Poll::Pending
+  }
+}
+
+impl<T> Drop for Lock<'_, T> {
+  fn drop(&mut self) {
+    let Some(id) = self.id else {
+      return;
+    };
+    let mut state = self.mutex.state.lock();
+    let next = match state.position(id) {
+      Some(i) => {
+        state.waiters.remove(i);
+        None
+      }
+      // 起こされた後に取得せずキャンセルされたら、起こす権利を次に回す
+      None if !state.locked => state.waiters.pop_front(),
+      None => None,
+    };
+    // wakeはstateのロックを外してから呼ぶ。同じmutexをpollし直すwakerでもデッドロックしない
+    drop(state);
+    if let Some((_, w)) = next {
+      w.wake();
+    }
+  }
+}
+
+pub struct AsyncMutexGuard<'a, T> {
+  mutex: &'a AsyncMutex<T>,
+}
+
+unsafe impl<T> Send for AsyncMutexGuard<'_, T> where T: Send {}
+// 共有すると&Tが他スレッドに渡るので、T: Syncが必要。自動実装だとT: Sendだけで付いてしまう
+unsafe impl<T> Sync for AsyncMutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.mutex.value.get() }
+  }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.mutex.value.get() }
+  }
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+  fn drop(&mut self) {
+    let mut state = self.mutex.state.lock();
+    state.locked = false;
+    let next = state.waiters.pop_front();
+    drop(state);
+    if let Some((_, w)) = next {
+      w.wake();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::sync::atomic::AtomicBool;
+  use std::sync::atomic::Ordering::Relaxed;
+  use std::task::Wake;
+
+  use super::*;
+
+  struct Woken(AtomicBool);
+
+  impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+      self.0.store(true, Relaxed);
+    }
+  }
+
+  // 起こされたタスクだけをpollする。誰も起こされないのに残っていたらデッドロック
+  fn run(tasks: Vec<Pin<Box<dyn Future<Output = ()> + '_>>>) {
+    let mut tasks: Vec<_> = tasks
+      .into_iter()
+      .map(|t| (t, Arc::new(Woken(AtomicBool::new(true)))))
+      .collect();
+    while !tasks.is_empty() {
+      let mut progressed = false;
+      tasks.retain_mut(|(task, woken)| {
+        if !woken.0.swap(false, Relaxed) {
+          return true;
+        }
+        progressed = true;
+        let waker = Waker::from(woken.clone());
+        task.as_mut().poll(&mut Context::from_waker(&waker)).is_pending()
+      });
+      assert!(progressed, "no task was woken");
+    }
+  }
+
+  // 一度だけPendingを返してexecutorに戻る
+  struct YieldNow(bool);
+
+  impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+      if self.0 {
+        return Poll::Ready(());
+      }
+      self.0 = true;
+      cx.waker().wake_by_ref();
+      Poll::Pending
+    }
+  }
+
+  #[test]
+  fn contended_tasks_complete() {
+    let m = AsyncMutex::new(Vec::new());
+    let m = &m;
+    let task = |id: u32| async move {
+      for i in 0..3 {
+        let mut g = m.lock().await;
+        g.push((id, i));
+        // ロックを持ったままexecutorに戻り、もう一方のタスクを待たせる
+        YieldNow(false).await;
+        g.push((id, i));
+      }
+    };
+    run(vec![Box::pin(task(0)), Box::pin(task(1))]);
+
+    let v = std::pin::pin!(m.lock());
+    let waker = Waker::noop();
+    let Poll::Ready(g) = v.poll(&mut Context::from_waker(waker)) else {
+      panic!("mutex should be unlocked");
+    };
+    assert_eq!(g.len(), 12);
+    // ロック中に他のタスクが割り込んでいない
+    for pair in g.chunks(2) {
+      assert_eq!(pair[0], pair[1]);
+    }
+  }
+
+  #[test]
+  fn repolled_waiter_does_not_strand_next() {
+    let m = AsyncMutex::new(0);
+    let noop = Waker::noop();
+    let mut held = std::pin::pin!(m.lock());
+    let Poll::Ready(g) = held.as_mut().poll(&mut Context::from_waker(noop)) else {
+      panic!("mutex should be unlocked");
+    };
+
+    let a_woken = Arc::new(Woken(AtomicBool::new(false)));
+    let b_woken = Arc::new(Woken(AtomicBool::new(false)));
+    let a_waker = Waker::from(a_woken.clone());
+    let b_waker = Waker::from(b_woken.clone());
+    let mut a = std::pin::pin!(m.lock());
+    let mut b = std::pin::pin!(m.lock());
+    // aを2回pollしても、待ち行列には1つしか入らない
+    assert!(a.as_mut().poll(&mut Context::from_waker(noop)).is_pending());
+    assert!(a.as_mut().poll(&mut Context::from_waker(&a_waker)).is_pending());
+    assert!(b.as_mut().poll(&mut Context::from_waker(&b_waker)).is_pending());
+
+    drop(g);
+    assert!(a_woken.0.load(Relaxed));
+    let Poll::Ready(ga) = a.as_mut().poll(&mut Context::from_waker(&a_waker)) else {
+      panic!("a should get the lock");
+    };
+    assert!(!b_woken.0.load(Relaxed));
+    // aの古いエントリではなく、bが起こされる
+    drop(ga);
+    assert!(b_woken.0.load(Relaxed));
+    assert!(b.as_mut().poll(&mut Context::from_waker(&b_waker)).is_ready());
+  }
+
+  // 起こされたその場で同じmutexの状態を見に来るwaker
+  struct Relock(Arc<AsyncMutex<i32>>, AtomicBool);
+
+  impl Wake for Relock {
+    fn wake(self: Arc<Self>) {
+      drop(self.0.state.lock());
+      self.1.store(true, Relaxed);
+    }
+  }
+
+  #[test]
+  fn cancelled_waiter_wakes_next_after_unlocking_state() {
+    let m = Arc::new(AsyncMutex::new(0));
+    let noop = Waker::noop();
+    let mut held = std::pin::pin!(m.lock());
+    let Poll::Ready(g) = held.as_mut().poll(&mut Context::from_waker(noop)) else {
+      panic!("mutex should be unlocked");
+    };
+
+    let b_woken = Arc::new(Relock(m.clone(), AtomicBool::new(false)));
+    let b_waker = Waker::from(b_woken.clone());
+    let mut a = Box::pin(m.lock());
+    let mut b = std::pin::pin!(m.lock());
+    assert!(a.as_mut().poll(&mut Context::from_waker(noop)).is_pending());
+    assert!(b.as_mut().poll(&mut Context::from_waker(&b_waker)).is_pending());
+
+    drop(g);
+    // 起こされたaが取らずに捨てられるとbが起こされる。stateを持ったまま起こすとここで止まる
+    drop(a);
+    assert!(b_woken.1.load(Relaxed));
+    assert!(b.as_mut().poll(&mut Context::from_waker(&b_waker)).is_ready());
+  }
+}
@@ -2,24 +2,57 @@ use std::{cell::UnsafeCell, ops::{Deref, DerefMut}, sync::atomic::AtomicU32};
 use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 
 use atomic_wait::{wait, wake_one};
+
+mod async_mutex;
+
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard};
+
+// ロックが取れなかったときの待ち方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+  // parkせずにスピンし続ける。クリティカルセクションがごく短いとき向け
+  Spin,
+  // 指定回数だけスピンしてからfutexで寝る
+  SpinThenPark(u32),
+  // スピンせずにすぐ寝る。クリティカルセクションが長いとき向け
+  Park,
+}
+
+impl Default for Strategy {
+  // Mutex::newが使う。100回スピンしてから寝る
+  fn default() -> Self {
+    Strategy::SpinThenPark(100)
+  }
+}
+
 pub struct Mutex<T> {
   /// 0 = unlocked, 1 = locked, 2 = locked with waiters
   state: AtomicU32,
+  strategy: Strategy,
   value: UnsafeCell<T>,
 }
 unsafe impl<T> Sync for Mutex<T> where T: Send {}
 
 impl<T> Mutex<T> {
   pub fn new(value: T) -> Self {
+    Self::with_strategy(value, Strategy::default())
+  }
+
+  pub fn with_strategy(value: T, strategy: Strategy) -> Self {
     Self {
       state: AtomicU32::new(0),
+      strategy,
       value: UnsafeCell::new(value),
     }
   }
 
-  pub fn lock(&self)-> MutexGuard<T> {
+  pub fn lock(&self)-> MutexGuard<'_, T> {
     if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
-      lock_contended(&self.state);
+      match self.strategy {
+        Strategy::Spin => lock_spin(&self.state),
+        Strategy::SpinThenPark(n) => lock_contended(&self.state, n),
+        Strategy::Park => lock_contended(&self.state, 0),
+      }
     }
 
     MutexGuard { mutex: self }
@@ -27,10 +60,17 @@ impl<T> Mutex<T> {
   
 }
 
-fn lock_contended(state: &AtomicU32) {
+// 2を書かないので、unlock側がwake_oneを呼ぶことはない
+fn lock_spin(state: &AtomicU32) {
+  while state.compare_exchange_weak(0, 1, Acquire, Relaxed).is_err() {
+    std::hint::spin_loop();
+  }
+}
+
+fn lock_contended(state: &AtomicU32, max_spin: u32) {
   let mut spin_count = 0;
 
-  while state.load(Relaxed) == 1 && spin_count < 100 {
+  while state.load(Relaxed) == 1 && spin_count < max_spin {
     spin_count += 1;
     std::hint::spin_loop();
   }
@@ -89,4 +129,21 @@ mod tests {
     println!("locked {} times in {:?}", *m.lock(), duration);
     assert!(*m.lock() == 500000);
   }
+
+  #[test]
+  fn strategies_under_contention() {
+    for strategy in [Strategy::Spin, Strategy::SpinThenPark(100), Strategy::Park] {
+      let m = Mutex::with_strategy(0, strategy);
+      std::thread::scope(|s| {
+        for _ in 0..8 {
+          s.spawn(|| {
+            for _ in 0..1000 {
+              *m.lock() += 1;
+            }
+          });
+        }
+      });
+      assert_eq!(*m.lock(), 8000, "{strategy:?}");
+    }
+  }
 }
@@ -0,0 +1,6 @@
+// Send/Syncにならないはずの型が、誤って実装されていないことを確かめる
+#[test]
+fn compile_fail() {
+  let t = trybuild::TestCases::new();
+  t.compile_fail("tests/ui/*.rs");
+}
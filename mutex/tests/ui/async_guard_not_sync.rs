@@ -0,0 +1,10 @@
+use std::cell::Cell;
+
+use mutex::AsyncMutexGuard;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+  // AsyncMutexGuardを共有すると&Tが他スレッドに渡るので、T: Syncが必要
+  assert_sync::<AsyncMutexGuard<'static, Cell<i32>>>();
+}
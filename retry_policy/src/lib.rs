@@ -0,0 +1,102 @@
+// SpinLock、RwLock、Arcがスピンして待つときの待ち方。どのクレートからも使えるように依存を持たない
+use std::hint::spin_loop;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+
+// ロックが取れなかったときにどう待つか
+// attemptは同じlock呼び出しの中で0から1ずつ増える
+// ポリシーはロックの中にあり、複数のスレッドが&selfから同時に使うので&selfで受け取る
+// 呼び出しごとの状態はattemptで渡し、呼び出しをまたぐ状態はAdaptiveのようにアトミックで持つ
+pub trait RetryPolicy {
+  fn backoff(&self, attempt: u32);
+
+  // ロックが取れたときに、それまでに失敗した回数を受け取る。待ち方を変えたいポリシーだけが使う
+  fn acquired(&self, _attempts: u32) {}
+}
+
+// 毎回spin_loopを1回だけ挟む。もともとのSpinLockと同じ動き
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spin;
+
+impl RetryPolicy for Spin {
+  fn backoff(&self, _attempt: u32) {
+    spin_loop();
+  }
+}
+
+// n回まではスピンし、それ以降はOSに他のスレッドを走らせてもらう
+#[derive(Debug, Clone, Copy)]
+pub struct YieldAfter(pub u32);
+
+impl RetryPolicy for YieldAfter {
+  fn backoff(&self, attempt: u32) {
+    if attempt < self.0 {
+      spin_loop();
+    } else {
+      thread::yield_now();
+    }
+  }
+}
+
+// 2^attempt回スピンする。1回あたりのスピン数はcapで頭打ち
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialCapped {
+  pub cap: u32,
+}
+
+impl RetryPolicy for ExponentialCapped {
+  fn backoff(&self, attempt: u32) {
+    let spins = 1u32.checked_shl(attempt).unwrap_or(u32::MAX).min(self.cap);
+    for _ in 0..spins {
+      spin_loop();
+    }
+  }
+}
+
+// 最近の競合具合に合わせて、スピンをやめてyieldに切り替えるまでの回数を変える
+// 競合が多いほど早くyieldする。待ち方を変えるだけなので、ロックの正しさには影響しない
+#[derive(Debug, Default)]
+pub struct Adaptive {
+  // ロックを取るまでに失敗した回数の指数移動平均。下位4bitは小数部
+  contention: AtomicU32,
+}
+
+impl Adaptive {
+  // 競合が無いときにyieldまでスピンする回数
+  const MAX_SPINS: u32 = 64;
+  // 1回の記録で平均に入れる失敗回数の上限。1回の長い待ちで平均が振り切れないようにする
+  const SAMPLE_CAP: u32 = 1024;
+
+  pub const fn new() -> Self {
+    Self { contention: AtomicU32::new(0) }
+  }
+
+  // 最近ロックを取るまでに失敗した平均回数(切り捨て)
+  pub fn contention(&self) -> u32 {
+    self.contention.load(Relaxed) >> 4
+  }
+
+  fn spin_limit(&self) -> u32 {
+    (Self::MAX_SPINS / (1 + self.contention())).max(1)
+  }
+}
+
+impl RetryPolicy for Adaptive {
+  fn backoff(&self, attempt: u32) {
+    if attempt < self.spin_limit() {
+      spin_loop();
+    } else {
+      thread::yield_now();
+    }
+  }
+
+  // 平均に1/8の重みで足す。切り上げで減らすので、競合が無くなれば0まで戻る
+  // 他のスレッドと同時に更新して1回分失われても、待ち方が少しずれるだけ
+  fn acquired(&self, attempts: u32) {
+    let sample = attempts.min(Self::SAMPLE_CAP) << 4;
+    let _ = self.contention.fetch_update(Relaxed, Relaxed, |avg| {
+      Some(avg - avg.div_ceil(8) + sample / 8)
+    });
+  }
+}
@@ -1,112 +1,705 @@
-use std::{cell::UnsafeCell, ops::{Deref, DerefMut}, sync::atomic::AtomicU32, u32};
-use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::{cell::UnsafeCell, ops::{Deref, DerefMut}, sync::atomic::AtomicU32};
+use std::sync::atomic::fence;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, SeqCst};
 
+use arc::Arc;
 use atomic_wait::{wait, wake_all, wake_one};
+use retry_policy::RetryPolicy;
 
-pub struct RwLock<T> {
+#[cfg(feature = "stress")]
+mod stress;
+
+#[cfg(feature = "stress")]
+pub use stress::stress_rwlock;
+
+// stateがwriter locked(u32::MAX)や待機中writerのビットと衝突しない最大のreader数
+pub const DEFAULT_MAX_READERS: u32 = u32::MAX / 2 - 1;
+
+// with_policyで作ったロックが、futexで寝る前にpolicyのbackoffを挟んでstateを見直す回数
+pub const SPIN_ATTEMPTS: u32 = 100;
+
+// MAX_READERSを超えるreaderは、readerが1つ解放されるまでブロックする
+pub struct RwLock<T, const MAX_READERS: u32 = DEFAULT_MAX_READERS> {
 	// readers count (0..=u32::MAX-1) or writer locked (u32::MAX)
 	// 2 * wait reader + wait writer ? 1:0 
 	state: AtomicU32,
 	writer_wake_counter: AtomicU32,
+	// futexで寝ている(寝ようとしている)スレッドの数。0ならwakeのシステムコールを省く
+	waiting_readers: AtomicU32,
+	waiting_writers: AtomicU32,
+	// read_nで、残りの分を足すと上限を超えるので寝ているスレッドの数。上限より下でreaderが抜けても起こす
+	waiting_batches: AtomicU32,
+	// テストでwakeを呼んだ回数を数える
+	#[cfg(test)]
+	wake_calls: AtomicU32,
+	// poll_writeでPendingを返したタスクのWaker。poll_writeを使ったロックだけが確保する
+	wakers: OnceLock<Box<Mutex<Vec<Waker>>>>,
+	// wakersの長さ。0ならガードのdropでMutexを取らない
+	async_waiters: AtomicU32,
+	// new_balancedで作ったときだけ持つ
+	balance: Option<Box<Balance>>,
+	// new_reader_preferringで作ったときだけtrue。待機中のwriterがいてもreaderを入れる
+	prefer_readers: bool,
+	// with_policyで作ったときだけ持つ
+	policy: Option<Box<dyn RetryPolicy + Send + Sync>>,
 	value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+// readerとwriterの順番を決めるための状態
+struct Balance {
+	// readerが待っている間に続けて取れるwriterの数
+	max_consecutive_writers: u32,
+	// 最後にreaderが入ってから取られた書き込みロックの数
+	consecutive_writers: AtomicU32,
+	// 一度でもブロックされて、まだ入れていないreaderの数
+	blocked_readers: AtomicU32,
+	// 0でなければreaderの番。writerはこの数のブロックされていたreaderが入るまでロックを取らない
+	reader_turn: AtomicU32,
+}
+
+unsafe impl<T, const MAX_READERS: u32> Sync for RwLock<T, MAX_READERS> where T: Send + Sync {}
 
 impl<T> RwLock<T> {
 	pub const fn new(value: T) -> Self {
+		Self::with_max_readers(value)
+	}
+
+	// readerが待っているときにwriterが続けてmax_consecutive_writers回取ったら、
+	// 次のwriterより先に待っているreaderを入れる
+	pub fn new_balanced(value: T, max_consecutive_writers: u32) -> Self {
+		assert!(max_consecutive_writers > 0, "max_consecutive_writers must be positive");
+		let mut lock = Self::with_max_readers(value);
+		lock.balance = Some(Box::new(Balance {
+			max_consecutive_writers,
+			consecutive_writers: AtomicU32::new(0),
+			blocked_readers: AtomicU32::new(0),
+			reader_turn: AtomicU32::new(0),
+		}));
+		lock
+	}
+
+	// readerが1つでも入っている間は、待っているwriterがいても後から来たreaderを入れる
+	// 読み込みが途切れなければwriterはずっと待つ
+	pub fn new_reader_preferring(value: T) -> Self {
+		let mut lock = Self::with_max_readers(value);
+		lock.prefer_readers = true;
+		lock
+	}
+
+	// 取れなかったときに、すぐには寝ずにSPIN_ATTEMPTS回までpolicyで待ちながら空くのを待つ
+	pub fn with_policy(value: T, policy: impl RetryPolicy + Send + Sync + 'static) -> Self {
+		let mut lock = Self::with_max_readers(value);
+		lock.policy = Some(Box::new(policy));
+		lock
+	}
+}
+
+impl<T, const MAX_READERS: u32> RwLock<T, MAX_READERS> {
+	// RwLock::<_, 2>::with_max_readers(value) のように上限を指定する
+	pub const fn with_max_readers(value: T) -> Self {
+		const { assert!(MAX_READERS > 0 && MAX_READERS <= DEFAULT_MAX_READERS, "invalid MAX_READERS") };
 		Self {
 			state: AtomicU32::new(0), //unlocked
 			writer_wake_counter: AtomicU32::new(0),
+			waiting_readers: AtomicU32::new(0),
+			waiting_writers: AtomicU32::new(0),
+			waiting_batches: AtomicU32::new(0),
+			#[cfg(test)]
+			wake_calls: AtomicU32::new(0),
+			wakers: OnceLock::new(),
+			async_waiters: AtomicU32::new(0),
+			balance: None,
+			prefer_readers: false,
+			policy: None,
 			value: UnsafeCell::new(value),
 		}
 	}
 
-	pub fn read(&self) -> ReadGuard<T> {
-		let mut s = self.state.load( Relaxed);
+	// &mut selfなので他に参照はなく、ロックを取らずに中身を触れる
+	pub fn get_mut(&mut self) -> &mut T {
+		self.value.get_mut()
+	}
 
-		loop {
-			if s % 2 == 0 {
-				assert!( s != u32::MAX - 2, "too many readers");
+	// selfを取るのでガードは残っていない。ロックせずに中身を取り出す
+	pub fn into_inner(self) -> T {
+		self.value.into_inner()
+	}
+
+	// 最後のArcを持っているならロックせずに&mut Tを返す。共有されていればNone
+	pub fn get_mut_from_arc(arc: &mut Arc<Self>) -> Option<&mut T> {
+		Arc::get_mut(arc).map(Self::get_mut)
+	}
 
+	pub fn read(&self) -> ReadGuard<'_, T, MAX_READERS> {
+		// 誰もいなければ1回のCASだけで取る。失敗したら返ってきた値からループを始める
+		match self.state.compare_exchange(0, 2, Acquire, Relaxed) {
+			Ok(_) => {
+				self.admit_readers(false);
+				ReadGuard { rwlock: self }
+			}
+			Err(s) => self.read_contended(s),
+		}
+	}
+
+	// sは直前に読んだstate
+	fn read_contended(&self, mut s: u32) -> ReadGuard<'_, T, MAX_READERS> {
+		let mut blocked = false;
+		let mut attempt = 0;
+
+		loop {
+			if self.readers_admitted(s) && s / 2 < MAX_READERS {
 				match self.state.
 				compare_exchange_weak(s, s + 2 , Acquire, Relaxed) {
-					Ok(_) => return ReadGuard { rwlock: self},
-					Err(e) => s = e,
+					Ok(_) => {
+						self.acquired(attempt);
+						self.admit_readers(blocked);
+						return ReadGuard { rwlock: self};
+					}
+					Err(e) => { s = e; continue; }
 				}
 			}
-			if s % 2 == 1 {
-					wait(&self.state, s);
-					s = self.state.load(Relaxed);
-				}
+			if self.backoff(&mut attempt) {
+				s = self.state.load(Relaxed);
+				continue;
+			}
+			// writerがいる(待っている)か、readerが上限に達している
+			if let Some(b) = &self.balance && !blocked {
+				blocked = true;
+				b.blocked_readers.fetch_add(1, Relaxed);
 			}
+			self.waiting_readers.fetch_add(1, SeqCst);
+			wait(&self.state, s);
+			self.waiting_readers.fetch_sub(1, Relaxed);
+			s = self.state.load(Relaxed);
 		}
+	}
 
-	pub fn write(&self) -> WriteGuard<T> {
+	// n個のReadGuardをまとめて取る。writerがいなければ1回のCASでreaderをn増やす
+	pub fn read_n(&self, n: u32) -> Vec<ReadGuard<'_, T, MAX_READERS>> {
+		// 自分で上限を埋めると、残りのreadで自分を待ち続けてしまう
+		assert!(n <= MAX_READERS, "read_n: n exceeds MAX_READERS");
 		let mut s = self.state.load(Relaxed);
-		
+		while self.readers_admitted(s) && s / 2 + n <= MAX_READERS {
+			match self.state.compare_exchange_weak(s, s + 2 * n, Acquire, Relaxed) {
+				Ok(_) => {
+					self.admit_readers(false);
+					return (0..n).map(|_| ReadGuard { rwlock: self }).collect();
+				}
+				Err(e) => s = e,
+			}
+		}
+		if n == 0 {
+			return Vec::new();
+		}
+		// writerがいる(待っている)ときは、まず1つを普通に取る
+		let first = self.read();
+		// 残りは待機中のwriterを無視して足す。1つずつreadすると、
+		// 自分のガードが空くのを待つwriterを自分が待つことになる
+		let rest = n - 1;
+		let mut s = self.state.load(Relaxed);
+		loop {
+			if s / 2 + rest <= MAX_READERS {
+				match self.state.compare_exchange_weak(s, s + 2 * rest, Acquire, Relaxed) {
+					Ok(_) => {
+						self.admit_readers(false);
+						break;
+					}
+					Err(e) => { s = e; continue; }
+				}
+			}
+			// ReadGuard::dropはstateを減らしてからwaiting_batchesを見るので、どちらかが相手を必ず観測する
+			self.waiting_batches.fetch_add(1, SeqCst);
+			self.waiting_readers.fetch_add(1, SeqCst);
+			wait(&self.state, s);
+			self.waiting_readers.fetch_sub(1, Relaxed);
+			self.waiting_batches.fetch_sub(1, Relaxed);
+			s = self.state.load(Relaxed);
+		}
+		let mut guards = vec![first];
+		guards.extend((0..rest).map(|_| ReadGuard { rwlock: self }));
+		guards
+	}
+
+	pub fn write(&self) -> WriteGuard<'_, T, MAX_READERS> {
+		// readと同じく、空いていれば1回のCASだけで取る。readerの番ならadmit_writerが手放す
+		match self.state.compare_exchange(0, u32::MAX, Acquire, Relaxed) {
+			Ok(_) => match self.admit_writer() {
+				Some(guard) => guard,
+				None => self.write_contended(self.state.load(Relaxed)),
+			},
+			Err(s) => self.write_contended(s),
+		}
+	}
+
+	// sは直前に読んだstate
+	fn write_contended(&self, mut s: u32) -> WriteGuard<'_, T, MAX_READERS> {
+		let mut attempt = 0;
 		loop {
+			if self.is_reader_turn() {
+				// readerの番になる前に立てられた待機中のビットが残っていると、ブロックされたreaderが入れない
+				// ビットを下ろして起こす。寝ているwriterは、このwriterが取った後のWriteGuard::dropで起こされる
+				if s != u32::MAX && !s.is_multiple_of(2) {
+					if let Err(e) = self.state.compare_exchange(s, s - 1, Relaxed, Relaxed) {
+						s = e;
+						continue;
+					}
+					fence(SeqCst);
+					if self.waiting_readers.load(Relaxed) > 0 {
+						self.wake_readers(true);
+					}
+				}
+				// readerの番が終わるまで寝る。admit_readersはreader_turnを0にしてからwaiting_writersを見るので、
+				// どちらかが相手を必ず観測する
+				self.waiting_writers.fetch_add(1, SeqCst);
+				let w = self.writer_wake_counter.load(Acquire);
+				if self.balance.as_ref().is_some_and(|b| b.reader_turn.load(SeqCst) > 0) {
+					wait(&self.writer_wake_counter, w);
+				}
+				self.waiting_writers.fetch_sub(1, Relaxed);
+				s = self.state.load(Relaxed);
+				continue;
+			}
+
 			if s <= 1 {
 				match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
-					Ok(_) => return WriteGuard { rwlock: self },
+					Ok(_) => match self.admit_writer() {
+						Some(guard) => {
+							self.acquired(attempt);
+							return guard;
+						}
+						None => { s = self.state.load(Relaxed); continue; }
+					},
 					Err(e) => { s = e; continue; }
 				}
 			}
 
-			if s % 2 == 0 {
+			if s.is_multiple_of(2) {
 				match self.state.compare_exchange(s, s + 1, Relaxed, Relaxed) {
 					Ok(_) => {}
 					Err(e) => { s = e; continue; }
 				}
 			}
 
+			if self.backoff(&mut attempt) {
+				s = self.state.load(Relaxed);
+				continue;
+			}
+
+			// waiting_writersを増やしてからstateを見る。WriteGuard::dropは逆順に見るので、
+			// どちらかが相手を必ず観測する
+			self.waiting_writers.fetch_add(1, SeqCst);
 			let w = self.writer_wake_counter.load(Acquire);
-			s = self.state.load(Relaxed);
+			s = self.state.load(SeqCst);
 
-			if s >= 2 {
+			// 待機中のビットが立っているときだけ寝る。readerだけが残っていて(偶数)ビットがなければ、
+			// 最後のreaderは誰も起こさないので、ループの先頭に戻ってビットを立て直す
+			if s >= 2 && !s.is_multiple_of(2) {
 				wait(&self.writer_wake_counter, w);
 				s = self.state.load(Relaxed);
-			} 
+			}
+			self.waiting_writers.fetch_sub(1, Relaxed);
 		}
 	}
+
+	// 読み込みロックを持っている間にcloneし、ガードを解放してから返す
+	pub fn read_cloned(&self) -> T
+	where
+		T: Clone,
+	{
+		T::clone(&self.read())
+	}
+
+	// 古い値はガードを解放してから返す
+	pub fn swap(&self, value: T) -> T {
+		let mut guard = self.write();
+		std::mem::replace(&mut *guard, value)
+	}
+
+	// 書き込みロックを持ったままpredで今の値を調べ、trueのときだけnewと入れ替える
+	// falseなら値には触らず、newをそのままErrで返す
+	pub fn update_if(&self, pred: impl FnOnce(&T) -> bool, new: T) -> Result<T, T> {
+		let mut guard = self.write();
+		if !pred(&guard) {
+			return Err(new);
+		}
+		Ok(std::mem::replace(&mut *guard, new))
+	}
+
+	// クロージャの間だけ書き込みロックを持つ。panicしてもガードのdropで解放される
+	pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+		f(&mut self.write())
+	}
+
+	// 取れなければwakerを登録してPendingを返す。ロックが空いたらwakerが呼ばれるので、もう一度pollする
+	pub fn poll_write(&self, waker: &Waker) -> Poll<WriteGuard<'_, T, MAX_READERS>> {
+		if let Some(guard) = self.try_write() {
+			return Poll::Ready(guard);
+		}
+		{
+			let mut wakers = self.wakers.get_or_init(Box::default).lock().unwrap();
+			if !wakers.iter().any(|w| w.will_wake(waker)) {
+				wakers.push(waker.clone());
+				self.async_waiters.store(wakers.len() as u32, SeqCst);
+			}
+		}
+		// 登録する前に解放されていたら、誰もwakerを呼ばない
+		match self.try_write() {
+			Some(guard) => Poll::Ready(guard),
+			None => Poll::Pending,
+		}
+	}
+
+	// 1回だけCASする。writerがいる(待っている)か、readerが上限に達していればNone。waitはしない
+	pub fn try_read(&self) -> Option<ReadGuard<'_, T, MAX_READERS>> {
+		let s = self.state.load(Relaxed);
+		if self.readers_admitted(s) && s / 2 < MAX_READERS && self.state.compare_exchange(s, s + 2, Acquire, Relaxed).is_ok() {
+			self.admit_readers(false);
+			return Some(ReadGuard { rwlock: self });
+		}
+		None
+	}
+
+	// 空いている(待機中のビットだけが立っている)ときに1回だけCASする。waitはしない
+	pub fn try_write(&self) -> Option<WriteGuard<'_, T, MAX_READERS>> {
+		let s = self.state.load(SeqCst);
+		if s <= 1 && !self.is_reader_turn() && self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed).is_ok() {
+			return self.admit_writer();
+		}
+		None
+	}
+
+	// stateがsのときにreaderが入ってよいか。上限は見ない
+	fn readers_admitted(&self, s: u32) -> bool {
+		s.is_multiple_of(2) || (self.prefer_readers && s != u32::MAX)
+	}
+
+	// policyがあって、まだSPIN_ATTEMPTS回に達していなければ1回待ってtrue。falseなら呼び出し側が寝る
+	fn backoff(&self, attempt: &mut u32) -> bool {
+		match &self.policy {
+			Some(p) if *attempt < SPIN_ATTEMPTS => {
+				p.backoff(*attempt);
+				*attempt += 1;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	// 取れるまでに待った回数をpolicyに知らせる
+	fn acquired(&self, attempts: u32) {
+		if let Some(p) = &self.policy {
+			p.acquired(attempts);
+		}
+	}
+
+	fn is_reader_turn(&self) -> bool {
+		self.balance.as_ref().is_some_and(|b| b.reader_turn.load(Acquire) > 0)
+	}
+
+	// stateをu32::MAXにした直後に呼ぶ。readerの番だと分かったらロックを手放してNoneを返す
+	fn admit_writer(&self) -> Option<WriteGuard<'_, T, MAX_READERS>> {
+		let guard = WriteGuard { rwlock: self };
+		if let Some(b) = &self.balance {
+			// CASのAcquireで、前のwriterがstateを0にする前に書いたreader_turnが見える
+			if b.reader_turn.load(Relaxed) > 0 {
+				// dropで0に戻すので、立っていた待機中のビットも消えてreaderが入れる
+				drop(guard);
+				return None;
+			}
+			b.consecutive_writers.fetch_add(1, Relaxed);
+		}
+		Some(guard)
+	}
+
+	// readerが入った直後、ReadGuardを返す前に呼ぶ。blockedはそのreaderが一度でもブロックされたか
+	fn admit_readers(&self, blocked: bool) {
+		let Some(b) = &self.balance else {
+			return;
+		};
+		b.consecutive_writers.store(0, Relaxed);
+		// readerの番はブロックされていたreaderのためのもの。待たずに入ったreaderは使わない
+		if blocked {
+			b.blocked_readers.fetch_sub(1, Relaxed);
+			// 最後に入ったreaderが番を終わらせ、write_contendedで寝ているwriterを起こす
+			if b.reader_turn.fetch_update(SeqCst, Relaxed, |t| t.checked_sub(1)) == Ok(1)
+				&& self.waiting_writers.load(SeqCst) > 0
+			{
+				self.wake_writer();
+			}
+		}
+	}
+
+	// fence(SeqCst)の後に呼ぶ
+	fn wake_async_waiters(&self) {
+		if self.async_waiters.load(Relaxed) == 0 {
+			return;
+		}
+		let Some(wakers) = self.wakers.get() else {
+			return;
+		};
+		let wakers = {
+			let mut wakers = wakers.lock().unwrap();
+			// 登録と同じMutexの中で0に戻す
+			self.async_waiters.store(0, Relaxed);
+			std::mem::take(&mut *wakers)
+		};
+		for w in wakers {
+			w.wake();
+		}
+	}
+
+	fn wake_writer(&self) {
+		self.writer_wake_counter.fetch_add(1, Release);
+		wake_one(&self.writer_wake_counter);
+		#[cfg(test)]
+		self.wake_calls.fetch_add(1, Relaxed);
+	}
+
+	fn wake_readers(&self, all: bool) {
+		if all {
+			wake_all(&self.state);
+		} else {
+			wake_one(&self.state);
+		}
+		#[cfg(test)]
+		self.wake_calls.fetch_add(1, Relaxed);
+	}
+
+	/// forgetされたReadGuardの分だけreaderの数を減らす。復旧用のツール向け
+	///
+	/// # Safety
+	///
+	/// n個のReadGuardが`mem::forget`などでdropされないまま失われていて、
+	/// そのガードを通した参照がもう使われないことを呼び出し側が保証する。
+	/// 生きているガードの分まで減らすと、readerがいる間にwriterがロックを取れてしまう
+	pub unsafe fn force_release_read(&self, n: u32) {
+		let s = self.state
+			.fetch_update(Release, Relaxed, |s| {
+				(s != u32::MAX && s / 2 >= n).then(|| s - 2 * n)
+			})
+			.expect("force_release_read: fewer readers than n");
+		let new = s - 2 * n;
+		if n > 0 && new == 1 {
+			// 待っているwriterが取れるようになった
+			self.wake_writer();
+		}
+		fence(SeqCst);
+		if s / 2 >= MAX_READERS && self.waiting_readers.load(Relaxed) > 0 {
+			self.wake_readers(true);
+		}
+		if new <= 1 {
+			self.wake_async_waiters();
+		}
+	}
+
+	/// 境界値テスト用にstateを直接書き換える。待っているスレッドは起こさない
+	///
+	/// # Safety
+	///
+	/// ガードの数と食い違うstateを書くと、ロックの排他性が壊れる。
+	/// テストで元の値に戻すことが前提
+	#[cfg(any(test, feature = "test-hooks"))]
+	#[doc(hidden)]
+	pub unsafe fn __set_state(&self, v: u32) {
+		self.state.store(v, Release);
+	}
+}
+
+// 2つのロックを常にアドレス順に取ることで、逆順に取るスレッドとのデッドロックを防ぐ
+fn address_order<A, B, const MA: u32, const MB: u32>(a: &RwLock<A, MA>, b: &RwLock<B, MB>) -> bool {
+	let a = a as *const RwLock<A, MA> as *const ();
+	let b = b as *const RwLock<B, MB> as *const ();
+	// 同じロックを2回取ると待っているwriterとの間でデッドロックする
+	assert!(a != b, "read_both/write_both called with the same lock twice");
+	a < b
 }
 
-pub struct ReadGuard<'a, T> {
-	rwlock: &'a RwLock<T>,
+pub fn read_both<'a, A, B, const MA: u32, const MB: u32>(
+	a: &'a RwLock<A, MA>,
+	b: &'a RwLock<B, MB>,
+) -> (ReadGuard<'a, A, MA>, ReadGuard<'a, B, MB>) {
+	if address_order(a, b) {
+		let ga = a.read();
+		(ga, b.read())
+	} else {
+		let gb = b.read();
+		(a.read(), gb)
+	}
 }
 
-impl<T> Drop for ReadGuard<'_, T> {
+pub fn write_both<'a, A, B, const MA: u32, const MB: u32>(
+	a: &'a RwLock<A, MA>,
+	b: &'a RwLock<B, MB>,
+) -> (WriteGuard<'a, A, MA>, WriteGuard<'a, B, MB>) {
+	if address_order(a, b) {
+		let ga = a.write();
+		(ga, b.write())
+	} else {
+		let gb = b.write();
+		(a.write(), gb)
+	}
+}
+
+pub struct ReadGuard<'a, T, const MAX_READERS: u32 = DEFAULT_MAX_READERS> {
+	rwlock: &'a RwLock<T, MAX_READERS>,
+}
+
+impl<T, const MAX_READERS: u32> Drop for ReadGuard<'_, T, MAX_READERS> {
 	fn drop(&mut self) {
-		if self.rwlock.state.fetch_sub(2, Release) == 3 {
+		// SeqCstで、read_nが寝る前に増やしたwaiting_batchesを読み落とさない
+		let s = self.rwlock.state.fetch_sub(2, SeqCst);
+		if s == 3 {
 			// 3->1 writer wait
-			self.rwlock.writer_wake_counter.fetch_add(1,Release);
-			wake_one(&self.rwlock.writer_wake_counter);
+			self.rwlock.wake_writer();
+		}
+		if self.rwlock.waiting_batches.load(SeqCst) > 0 {
+			// read_nは何個空けば入れるかわからないので、全員起こして数え直させる
+			self.rwlock.wake_readers(true);
+		} else if s / 2 == MAX_READERS {
+			// 上限で待っているreaderのために1つ空いた
+			fence(SeqCst);
+			if self.rwlock.waiting_readers.load(Relaxed) > 0 {
+				self.rwlock.wake_readers(false);
+			}
+		}
+		if s <= 3 {
+			// 最後のreaderだったので、poll_writeで待っているタスクが取れる
+			fence(SeqCst);
+			self.rwlock.wake_async_waiters();
 		}
 	}
 }
 
 
-impl<T> Deref for ReadGuard<'_, T> {
+impl<'a, T, const MAX_READERS: u32> ReadGuard<'a, T, MAX_READERS> {
+	// 自分が唯一のreaderならwriterになる。失敗したらそのままreaderとして返す
+	// 1 reader(2か、待機中writerがいれば3) -> u32::MAX を1回のCASで行うので、
+	// 一度0に戻して他のwriterに割り込まれることはない
+	pub fn try_upgrade(self) -> Result<WriteGuard<'a, T, MAX_READERS>, Self> {
+		let rwlock = self.rwlock;
+		let mut s = rwlock.state.load(Relaxed);
+		while s == 2 || s == 3 {
+			match rwlock.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+				Ok(_) => {
+					// readerの分はstateから消えたので、ReadGuardのdropは走らせない
+					std::mem::forget(self);
+					return Ok(WriteGuard { rwlock });
+				}
+				Err(e) => s = e,
+			}
+		}
+		Err(self)
+	}
+
+	// 読み込みロックを持ったまま、中身の一部だけを見せるガードにする
+	pub fn map<U: ?Sized>(self, f: impl FnOnce(&T) -> &U) -> MappedReadGuard<'a, T, U, MAX_READERS> {
+		let value = NonNull::from(f(unsafe { &*self.rwlock.value.get() }));
+		MappedReadGuard { _guard: self, value }
+	}
+}
+
+impl<T, const MAX_READERS: u32> Deref for ReadGuard<'_, T, MAX_READERS> {
 	type Target = T;
 	fn deref(&self) -> &T {
 		unsafe { &*self.rwlock.value.get() }
 	}
 }
 
-pub struct WriteGuard<'a, T> {
-	rwlock: &'a RwLock<T>,
+// ReadGuard::mapが返す。元のガードをそのまま持つので、dropでの解放も元のガードと同じ
+pub struct MappedReadGuard<'a, T, U: ?Sized, const MAX_READERS: u32 = DEFAULT_MAX_READERS> {
+	_guard: ReadGuard<'a, T, MAX_READERS>,
+	// _guardが生きている間だけ有効な、中身の一部を指すポインタ
+	value: NonNull<U>,
+}
+
+impl<T, U: ?Sized, const MAX_READERS: u32> Deref for MappedReadGuard<'_, T, U, MAX_READERS> {
+	type Target = U;
+	fn deref(&self) -> &U {
+		unsafe { self.value.as_ref() }
+	}
+}
+
+pub struct WriteGuard<'a, T, const MAX_READERS: u32 = DEFAULT_MAX_READERS> {
+	rwlock: &'a RwLock<T, MAX_READERS>,
 }
 
-impl<T> Drop for WriteGuard<'_, T> {
+impl<T, const MAX_READERS: u32> Drop for WriteGuard<'_, T, MAX_READERS> {
 	fn drop(&mut self) {
+		let rwlock = self.rwlock;
+		if let Some(b) = &rwlock.balance {
+			// 入ったreaderはガードを返す前にblocked_readersを減らすので、ここで数えた分は必ず入ってくる
+			let blocked = b.blocked_readers.load(Relaxed);
+			if blocked > 0 && b.consecutive_writers.load(Relaxed) >= b.max_consecutive_writers {
+				b.consecutive_writers.store(0, Relaxed);
+				b.reader_turn.store(blocked, Relaxed);
+			}
+		}
 		self.rwlock.state.store(0, Release);
-		self.rwlock.writer_wake_counter.fetch_add(1, Release);
-		wake_one(&self.rwlock.writer_wake_counter);
-		wake_all(&self.rwlock.state);
+		// 待っているスレッドが一度もいなければシステムコールは呼ばない
+		fence(SeqCst);
+		if self.rwlock.waiting_writers.load(Relaxed) > 0 {
+			self.rwlock.wake_writer();
+		}
+		if self.rwlock.waiting_readers.load(Relaxed) > 0 {
+			self.rwlock.wake_readers(true);
+		}
+		self.rwlock.wake_async_waiters();
 	}
 }
 
-impl<T> Deref for WriteGuard<'_, T> {
+impl<'a, T, const MAX_READERS: u32> WriteGuard<'a, T, MAX_READERS> {
+	// 書き込みロックを持ったまま、中身の一部だけを書き換えられるガードにする
+	pub fn map<U: ?Sized>(self, f: impl FnOnce(&mut T) -> &mut U) -> MappedWriteGuard<'a, T, U, MAX_READERS> {
+		let value = NonNull::from(f(unsafe { &mut *self.rwlock.value.get() }));
+		MappedWriteGuard { _guard: self, value, _marker: PhantomData }
+	}
+
+	// 書き込みロックを1つのreaderに置き換える。u32::MAXから直接2にするので、間に他のwriterは入れない
+	// 待っているreaderは起こすが、writerは起こさない。writerが待っていれば待機中のビットを立て直し、
+	// 最後のreaderのdropで起こされるようにする
+	pub fn downgrade(self) -> ReadGuard<'a, T, MAX_READERS> {
+		let rwlock = self.rwlock;
+		// 書き込みロックの解放はここで行うので、WriteGuardのdropは走らせない
+		std::mem::forget(self);
+		rwlock.state.store(2, Release);
+		// writerはwaiting_writersを増やしてからstateを見る。逆順に見るので、どちらかが相手を必ず観測する
+		fence(SeqCst);
+		if rwlock.waiting_writers.load(Relaxed) > 0 {
+			// 自分のreaderがいる間は0や1に戻らない
+			rwlock.state.fetch_or(1, Relaxed);
+		}
+		rwlock.admit_readers(false);
+		if rwlock.waiting_readers.load(Relaxed) > 0 {
+			rwlock.wake_readers(true);
+		}
+		ReadGuard { rwlock }
+	}
+
+	// 値をムーブしてfに渡し、返ってきた値を書き戻す
+	// fがpanicすると中身が空のままになるので、その場合はプロセスをabortする
+	// (Defaultで埋めるとT: Defaultが必要になり、このメソッドの意味がなくなる)
+	pub fn with_owned<R>(&mut self, f: impl FnOnce(T) -> (T, R)) -> R {
+		struct AbortOnPanic;
+		impl Drop for AbortOnPanic {
+			fn drop(&mut self) {
+				std::process::abort();
+			}
+		}
+
+		let slot = self.rwlock.value.get();
+		let bomb = AbortOnPanic;
+		let (value, r) = f(unsafe { slot.read() });
+		unsafe { slot.write(value) };
+		std::mem::forget(bomb);
+		r
+	}
+}
+
+impl<T, const MAX_READERS: u32> Deref for WriteGuard<'_, T, MAX_READERS> {
 	type Target = T;
 	
 	fn deref(&self) -> &Self::Target {
@@ -114,12 +707,34 @@ impl<T> Deref for WriteGuard<'_, T> {
 	}
 }
 
-impl<T> DerefMut for WriteGuard<'_, T> {
+impl<T, const MAX_READERS: u32> DerefMut for WriteGuard<'_, T, MAX_READERS> {
 	fn deref_mut(&mut self) -> &mut T {
 		unsafe { &mut *self.rwlock.value.get() }
 	}
 }
 
+// WriteGuard::mapが返す。元のガードをそのまま持つので、dropでの解放も元のガードと同じ
+pub struct MappedWriteGuard<'a, T, U: ?Sized, const MAX_READERS: u32 = DEFAULT_MAX_READERS> {
+	_guard: WriteGuard<'a, T, MAX_READERS>,
+	value: NonNull<U>,
+	// &mut Uと同じくUについて不変にする。NonNullのままだと共変になり、
+	// MappedWriteGuard<.., &'static str>を短い&strのガードにして、dangleする値を書き込めてしまう
+	_marker: PhantomData<&'a mut U>,
+}
+
+impl<T, U: ?Sized, const MAX_READERS: u32> Deref for MappedWriteGuard<'_, T, U, MAX_READERS> {
+	type Target = U;
+	fn deref(&self) -> &U {
+		unsafe { self.value.as_ref() }
+	}
+}
+
+impl<T, U: ?Sized, const MAX_READERS: u32> DerefMut for MappedWriteGuard<'_, T, U, MAX_READERS> {
+	fn deref_mut(&mut self) -> &mut U {
+		unsafe { self.value.as_mut() }
+	}
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -129,4 +744,706 @@ mod tests {
     fn it_works() {
   
     }
+
+    // 0 = unlocked, 2n = reader n個, +1 = 待機中のwriter, u32::MAX = writer
+    #[test]
+    fn state_machine_transitions() {
+		let lock = RwLock::new(());
+		let state = || lock.state.load(Relaxed);
+
+		// 0 -> reader -> 0
+		let r1 = lock.read();
+		assert_eq!(state(), 2);
+		let r2 = lock.read();
+		assert_eq!(state(), 4);
+		drop(r1);
+		assert_eq!(state(), 2);
+		drop(r2);
+		assert_eq!(state(), 0);
+
+		// 0 -> writer -> 0
+		let w = lock.write();
+		assert_eq!(state(), u32::MAX);
+		drop(w);
+		assert_eq!(state(), 0);
+
+		// reader + 待機中writer(3) -> 最後のreaderのdropで1になり、writerを起こす
+		let r = lock.read();
+		unsafe { lock.__set_state(3) };
+		let wakes = lock.writer_wake_counter.load(Relaxed);
+		drop(r);
+		assert_eq!(state(), 1);
+		assert_eq!(lock.writer_wake_counter.load(Relaxed), wakes + 1);
+
+		// 1 -> writer: 待機中のビットは取得時に消える
+		let w = lock.write();
+		assert_eq!(state(), u32::MAX);
+		drop(w);
+		assert_eq!(state(), 0);
+
+		// reader + 待機中writer -> try_upgradeでビットごとu32::MAXになる
+		let r = lock.read();
+		unsafe { lock.__set_state(3) };
+		let Ok(w) = r.try_upgrade() else { panic!("sole reader should upgrade") };
+		assert_eq!(state(), u32::MAX);
+		drop(w);
+		assert_eq!(state(), 0);
+
+		// 本物のwriterを待たせる: 2 -> 3 -> (drop) 1 -> u32::MAX -> 0
+		let r = lock.read();
+		std::thread::scope(|s| {
+			let t = s.spawn(|| {
+				let w = lock.write();
+				let held = lock.state.load(Relaxed);
+				drop(w);
+				held
+			});
+			while state() != 3 {
+				std::thread::yield_now();
+			}
+			drop(r);
+			assert_eq!(t.join().unwrap(), u32::MAX);
+		});
+		assert_eq!(state(), 0);
+    }
+
+    #[test]
+    fn write_both_opposite_order() {
+		let a = RwLock::new(0);
+		let b = RwLock::new(0);
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				for _ in 0..10000 {
+					let (mut x, mut y) = write_both(&a, &b);
+					*x += 1;
+					*y += 1;
+				}
+			});
+			s.spawn(|| {
+				for _ in 0..10000 {
+					let (mut y, mut x) = write_both(&b, &a);
+					*x += 1;
+					*y += 1;
+				}
+			});
+		});
+		let (x, y) = read_both(&a, &b);
+		assert_eq!((*x, *y), (20000, 20000));
+    }
+
+    #[test]
+    fn max_readers_blocks_extra_reader() {
+		use std::sync::atomic::AtomicBool;
+		use std::time::Duration;
+
+		let lock = RwLock::<_, 2>::with_max_readers(5);
+		let acquired = AtomicBool::new(false);
+		let r1 = lock.read();
+		let r2 = lock.read();
+		assert_eq!(lock.state.load(Relaxed), 4);
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				let r3 = lock.read();
+				acquired.store(true, Relaxed);
+				assert_eq!(*r3, 5);
+			});
+			std::thread::sleep(Duration::from_millis(100));
+			// 3つ目のreaderは上限でブロックされている
+			assert!(!acquired.load(Relaxed));
+			drop(r1);
+		});
+		assert!(acquired.load(Relaxed));
+		assert_eq!(*r2, 5);
+    }
+
+    #[test]
+    fn default_max_readers_boundary() {
+		use std::sync::atomic::AtomicBool;
+		use std::time::Duration;
+
+		let lock = RwLock::new(());
+		let acquired = AtomicBool::new(false);
+		// 上限の1つ手前までreaderがいることにする
+		unsafe { lock.__set_state(2 * (DEFAULT_MAX_READERS - 1)); }
+		let last = lock.read();
+		assert_eq!(lock.state.load(Relaxed), 2 * DEFAULT_MAX_READERS);
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				let _r = lock.read();
+				acquired.store(true, Relaxed);
+			});
+			std::thread::sleep(Duration::from_millis(100));
+			assert!(!acquired.load(Relaxed));
+			drop(last);
+		});
+		assert!(acquired.load(Relaxed));
+		assert_eq!(lock.state.load(Relaxed), 2 * (DEFAULT_MAX_READERS - 1));
+		unsafe { lock.__set_state(0); }
+		drop(lock.write());
+    }
+
+    #[test]
+    fn no_wake_without_contention() {
+		let lock = RwLock::new(0);
+		for _ in 0..100 {
+			*lock.write() += 1;
+			drop(lock.read());
+			drop((lock.read(), lock.read()));
+		}
+		assert_eq!(lock.wake_calls.load(Relaxed), 0);
+
+		// 競合があればwakeして正しく渡す
+		let w = lock.write();
+		std::thread::scope(|s| {
+			s.spawn(|| assert!(*lock.read() >= 101));
+			s.spawn(|| *lock.write() += 1);
+			while lock.waiting_readers.load(Relaxed) + lock.waiting_writers.load(Relaxed) < 2 {
+				std::thread::yield_now();
+			}
+			let mut w = w;
+			*w += 1;
+		});
+		assert!(lock.wake_calls.load(Relaxed) > 0);
+		assert_eq!(*lock.read(), 102);
+    }
+
+    #[test]
+    fn swap_returns_old_value() {
+		let lock = RwLock::new(String::from("old"));
+		assert_eq!(lock.swap(String::from("new")), "old");
+		std::thread::scope(|s| {
+			for _ in 0..4 {
+				s.spawn(|| assert_eq!(*lock.read(), "new"));
+			}
+		});
+		// 書き込みロックは解放されている
+		drop(lock.write());
+    }
+
+    #[test]
+    fn try_methods_fail_while_write_locked() {
+		let lock = RwLock::new(0);
+		let mut w = lock.write();
+		assert!(lock.try_read().is_none());
+		assert!(lock.try_write().is_none());
+		*w = 1;
+		drop(w);
+
+		let r = lock.try_read().unwrap();
+		assert_eq!(*r, 1);
+		// readerがいる間は書き込めない
+		assert!(lock.try_write().is_none());
+		drop(r);
+		*lock.try_write().unwrap() += 1;
+		assert_eq!(*lock.read(), 2);
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn update_if_swaps_only_when_predicate_holds() {
+		let lock = RwLock::new(1);
+		assert_eq!(lock.update_if(|v| *v == 1, 2), Ok(1));
+		assert_eq!(*lock.read(), 2);
+		assert_eq!(lock.update_if(|v| *v == 1, 3), Err(3));
+		assert_eq!(*lock.read(), 2);
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn read_cloned_is_independent_snapshot() {
+		let lock = RwLock::new(vec![1, 2]);
+		let snapshot = lock.read_cloned();
+		// ガードは残っていないので書き込める
+		std::thread::scope(|s| {
+			s.spawn(|| lock.write().push(3));
+		});
+		assert_eq!(snapshot, [1, 2]);
+		assert_eq!(*lock.read(), [1, 2, 3]);
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn with_write_from_threads() {
+		let lock = RwLock::new(Vec::new());
+		std::thread::scope(|s| {
+			for i in 0..4 {
+				let lock = &lock;
+				s.spawn(move || {
+					for j in 0..10 {
+						lock.with_write(|v| v.push(i * 10 + j));
+					}
+				});
+			}
+		});
+		let mut v = lock.with_write(std::mem::take);
+		v.sort();
+		assert_eq!(v, (0..40).collect::<Vec<_>>());
+
+		// panicしても書き込みロックは解放される
+		let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+			lock.with_write(|_| panic!())
+		}));
+		assert!(r.is_err());
+		assert!(lock.read().is_empty());
+    }
+
+    #[test]
+    fn with_owned_by_value_transform() {
+		// 値を消費して変換するメソッドしか持たない型
+		struct Builder(Vec<&'static str>);
+		impl Builder {
+			fn push(mut self, s: &'static str) -> Self {
+				self.0.push(s);
+				self
+			}
+		}
+
+		let lock = RwLock::new(Builder(vec!["a"]));
+		let len = lock.write().with_owned(|b| {
+			let b = b.push("b");
+			let len = b.0.len();
+			(b, len)
+		});
+		assert_eq!(len, 2);
+		assert_eq!(lock.read().0, ["a", "b"]);
+    }
+
+    #[test]
+    fn poll_write_wakes_pending_task() {
+		use std::sync::Arc;
+		use std::sync::atomic::AtomicBool;
+		use std::task::Wake;
+
+		struct Woken(AtomicBool);
+		impl Wake for Woken {
+			fn wake(self: Arc<Self>) {
+				self.0.store(true, Relaxed);
+			}
+		}
+
+		let lock = RwLock::new(0);
+		let woken = Arc::new(Woken(AtomicBool::new(false)));
+		let waker = Waker::from(woken.clone());
+
+		// writerが持っている間はPending。dropでタスクが起こされる
+		let w = lock.write();
+		assert!(lock.poll_write(&waker).is_pending());
+		assert!(!woken.0.load(Relaxed));
+		drop(w);
+		assert!(woken.0.swap(false, Relaxed));
+		let Poll::Ready(mut w) = lock.poll_write(&waker) else { panic!("lock should be free") };
+		*w += 1;
+		drop(w);
+
+		// 最後のreaderのdropでも起こされる
+		let (r1, r2) = (lock.read(), lock.read());
+		assert!(lock.poll_write(&waker).is_pending());
+		drop(r1);
+		assert!(!woken.0.load(Relaxed));
+		drop(r2);
+		assert!(woken.0.load(Relaxed));
+		assert!(lock.poll_write(&waker).is_ready());
+
+		// ブロッキングのAPIはそのまま使える
+		assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn force_release_leaked_reader() {
+		let lock = RwLock::new(0);
+		std::mem::forget(lock.read());
+		std::thread::scope(|s| {
+			s.spawn(|| *lock.write() += 1);
+			// writerが待機中のビットを立てて寝るまで待つ
+			while lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			// 寝ているwriterを起こさないとscopeが終わらない
+			unsafe { lock.force_release_read(1) };
+		});
+		assert_eq!(*lock.read(), 1);
+
+		let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { lock.force_release_read(1) }));
+		assert!(r.is_err());
+    }
+
+    #[test]
+    fn read_n_adds_readers_at_once() {
+		let lock = RwLock::new(1);
+		let guards = lock.read_n(4);
+		assert_eq!(guards.len(), 4);
+		assert_eq!(lock.state.load(Relaxed), 8);
+		assert!(guards.iter().all(|g| **g == 1));
+		drop(guards);
+		assert_eq!(lock.state.load(Relaxed), 0);
+
+		// writerが持っている間は1つずつ待つ
+		let w = lock.write();
+		std::thread::scope(|s| {
+			let t = s.spawn(|| lock.read_n(3).len());
+			std::thread::yield_now();
+			drop(w);
+			assert_eq!(t.join().unwrap(), 3);
+		});
+		assert!(lock.read_n(0).is_empty());
+    }
+
+    #[test]
+    fn read_n_wakes_when_readers_drop_below_cap() {
+		let lock = RwLock::<u32, 4>::with_max_readers(1);
+		let a = lock.read();
+		let b = lock.read();
+		std::thread::scope(|s| {
+			let t = s.spawn(|| lock.read_n(4).len());
+			// 1つ目を取って、残りの3つが入るのを待っている
+			while lock.waiting_batches.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			// 上限より下で抜けても起こされる
+			drop(a);
+			drop(b);
+			assert_eq!(t.join().unwrap(), 4);
+		});
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    // 1スレッドでread/writeを繰り返し、最初のCASで取る今の経路と、
+    // stateを読んでからループに入る以前の経路の1回あたりの時間を並べて表示する
+    #[cfg(feature = "bench")]
+    #[test]
+    fn bench_uncontended() {
+		use std::time::{Duration, Instant};
+		const N: u32 = 1_000_000;
+		let per_op = |d: Duration| d.as_nanos() as f64 / f64::from(N);
+		let time = |f: &mut dyn FnMut()| {
+			let start = Instant::now();
+			for _ in 0..N {
+				f();
+			}
+			per_op(start.elapsed())
+		};
+		let lock = RwLock::new(0u32);
+		let mut sum = 0u64;
+
+		let write_new = time(&mut || *lock.write() += 1);
+		let write_old = time(&mut || *lock.write_contended(lock.state.load(Relaxed)) += 1);
+		let read_new = time(&mut || sum += u64::from(*lock.read()));
+		let read_old = time(&mut || sum += u64::from(*lock.read_contended(lock.state.load(Relaxed))));
+
+		println!("uncontended write: {write_new:.1}ns/op (load+loop: {write_old:.1}ns/op)");
+		println!("uncontended read: {read_new:.1}ns/op (load+loop: {read_old:.1}ns/op)");
+		assert_eq!(sum, 2 * u64::from(N) * u64::from(2 * N));
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn downgrade_keeps_written_value() {
+		let lock = RwLock::new(0);
+		let mut w = lock.write();
+		*w = 1;
+		let r = w.downgrade();
+		assert_eq!(*r, 1);
+		std::thread::scope(|s| {
+			// 他のreaderは入れる
+			s.spawn(|| assert_eq!(*lock.read(), 1));
+		});
+		assert!(lock.try_write().is_none());
+		assert_eq!(*r, 1);
+		drop(r);
+		assert_eq!(lock.state.load(Relaxed), 0);
+
+		// 書き込みロック中に待っていたwriterは、downgradeしたreaderが離れた後に入る
+		let mut w = lock.write();
+		std::thread::scope(|s| {
+			s.spawn(|| *lock.write() += 1);
+			while lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			*w = 10;
+			let r = w.downgrade();
+			std::thread::sleep(std::time::Duration::from_millis(10));
+			assert_eq!(*r, 10);
+		});
+		assert_eq!(*lock.read(), 11);
+    }
+
+    #[test]
+    fn mapped_guards_keep_lock() {
+		let lock = RwLock::new((1u32, String::from("a")));
+		let mut name = lock.write().map(|(_, s)| s);
+		name.push('b');
+		assert!(lock.try_read().is_none());
+		drop(name);
+
+		let name = lock.read().map(|(_, s)| s.as_str());
+		assert_eq!(&*name, "ab");
+		// 読み込みロックのままなので、他のreaderは入れるがwriterは入れない
+		assert_eq!(lock.read().0, 1);
+		assert!(lock.try_write().is_none());
+		drop(name);
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn get_mut_and_into_inner() {
+		let mut lock = RwLock::new(vec![1]);
+		lock.get_mut().push(2);
+		assert_eq!(*lock.read(), [1, 2]);
+		lock.write().push(3);
+		assert_eq!(lock.into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn get_mut_from_unique_arc() {
+		let mut a = Arc::new(RwLock::new(1));
+		*RwLock::get_mut_from_arc(&mut a).unwrap() += 1;
+		let b = a.clone();
+		assert!(RwLock::get_mut_from_arc(&mut a).is_none());
+		drop(b);
+		assert_eq!(RwLock::get_mut_from_arc(&mut a).copied(), Some(2));
+    }
+
+    #[test]
+    fn write_after_read_drain() {
+		use std::sync::atomic::AtomicBool;
+
+		let lock = RwLock::new(());
+		let acquired = AtomicBool::new(false);
+		let readers = lock.read_n(3);
+		std::thread::scope(|s| {
+			let t = s.spawn(|| {
+				let _w = lock.write();
+				acquired.store(true, Relaxed);
+			});
+			// writerがビットを立てて寝るまで待つ
+			while lock.state.load(Relaxed) != 7 || lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			for r in readers {
+				// readerが残っている間はwriterはまだ取れていない
+				assert!(!acquired.load(Relaxed));
+				drop(r);
+				for _ in 0..10 {
+					std::thread::yield_now();
+				}
+			}
+			t.join().unwrap();
+			assert!(acquired.load(Relaxed));
+		});
+		assert_eq!(lock.state.load(Relaxed), 0);
+
+		// writerがu32::MAXを見て寝た後、readerが割り込んでビットのない偶数になっても、
+		// writerはビットを立て直して最後のreaderに起こしてもらう
+		for _ in 0..100 {
+			let w = lock.write();
+			std::thread::scope(|s| {
+				let t = s.spawn(|| drop(lock.write()));
+				while lock.waiting_writers.load(Relaxed) == 0 {
+					std::thread::yield_now();
+				}
+				drop(w);
+				let r = lock.read();
+				// 起きたwriterがreaderを見たら、ビットを立てて待つはず
+				while lock.state.load(Relaxed) != 3 && !t.is_finished() {
+					std::thread::yield_now();
+				}
+				drop(r);
+				t.join().unwrap();
+			});
+		}
+    }
+
+    #[test]
+    fn balanced_admits_waiting_reader() {
+		use std::sync::atomic::AtomicBool;
+
+		const N: u32 = 3;
+		let lock = RwLock::new_balanced(0u32, N);
+		let stop = AtomicBool::new(false);
+		let w = lock.write();
+		let start = *w;
+		std::thread::scope(|s| {
+			let reader = s.spawn(|| *lock.read());
+			while lock.balance.as_ref().unwrap().blocked_readers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			for _ in 0..4 {
+				s.spawn(|| {
+					while !stop.load(Relaxed) {
+						*lock.write() += 1;
+					}
+				});
+			}
+			while lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			drop(w);
+			let seen = reader.join().unwrap();
+			stop.store(true, Relaxed);
+			// readerが待っている間に入れたwriterはN回まで
+			assert!(seen - start <= N, "reader waited for {} writers", seen - start);
+		});
+		let b = lock.balance.as_ref().unwrap();
+		assert_eq!(b.reader_turn.load(Relaxed), 0);
+		assert_eq!(b.blocked_readers.load(Relaxed), 0);
+		drop(lock.write());
+    }
+
+    #[test]
+    fn reader_turn_clears_stale_writer_bit() {
+		let lock = RwLock::new_balanced(0u32, 1);
+		let b = lock.balance.as_ref().unwrap();
+		b.reader_turn.store(1, Relaxed);
+		// 待たずに入ったreaderは、ブロックされていたreaderの番を使わない
+		drop(lock.read());
+		assert_eq!(b.reader_turn.load(Relaxed), 1);
+
+		// readerの番になった後に、別のwriterが待機中のビットを立てた状態
+		lock.state.store(1, Relaxed);
+		std::thread::scope(|s| {
+			let reader = s.spawn(|| *lock.read());
+			while b.blocked_readers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			// ビットを下ろしてreaderを先に入れる。残ったままだと両方が止まる
+			*lock.write() += 1;
+			assert_eq!(reader.join().unwrap(), 0);
+		});
+		assert_eq!(*lock.read(), 1);
+		assert_eq!(b.reader_turn.load(Relaxed), 0);
+		assert_eq!(lock.state.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn writer_sleeps_through_reader_turn() {
+		let lock = RwLock::new_balanced(0u32, 1);
+		let b = lock.balance.as_ref().unwrap();
+		b.blocked_readers.store(1, Relaxed);
+		b.reader_turn.store(1, Relaxed);
+		std::thread::scope(|s| {
+			let writer = s.spawn(|| *lock.write() += 1);
+			// writerは空回りせずにwaiting_writersに入って寝る
+			while lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			assert!(!writer.is_finished());
+			let wakes = lock.wake_calls.load(Relaxed);
+			// ブロックされていたreaderが入って番が終わると、writerが起こされる
+			lock.admit_readers(true);
+			assert_eq!(lock.wake_calls.load(Relaxed), wakes + 1);
+			writer.join().unwrap();
+		});
+		assert_eq!(b.reader_turn.load(Relaxed), 0);
+		assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn reader_preferring_admits_readers_past_waiting_writer() {
+		let lock = RwLock::new_reader_preferring(0u32);
+		let r = lock.read();
+		std::thread::scope(|s| {
+			let writer = s.spawn(|| *lock.write() += 1);
+			while lock.state.load(Relaxed) != 3 {
+				std::thread::yield_now();
+			}
+			// 待機中のビットが立っていても、readerは入れる
+			let r2 = lock.try_read().expect("reader should pass a waiting writer");
+			let more = lock.read_n(2);
+			assert_eq!(lock.state.load(Relaxed), 9);
+			assert!(!writer.is_finished());
+			drop((r, r2, more));
+			writer.join().unwrap();
+		});
+		assert_eq!(*lock.read(), 1);
+
+		// 通常のRwLockでは、待っているwriterより先にreaderは入れない
+		let lock = RwLock::new(0u32);
+		let r = lock.read();
+		std::thread::scope(|s| {
+			s.spawn(|| *lock.write() += 1);
+			while lock.state.load(Relaxed) != 3 {
+				std::thread::yield_now();
+			}
+			assert!(lock.try_read().is_none());
+			drop(r);
+		});
+    }
+
+    #[test]
+    fn policy_backs_off_before_sleeping() {
+		static ATTEMPTS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+		static ACQUIRED: AtomicU32 = AtomicU32::new(u32::MAX);
+		struct Recording;
+		impl RetryPolicy for Recording {
+			fn backoff(&self, attempt: u32) {
+				ATTEMPTS.lock().unwrap().push(attempt);
+			}
+			fn acquired(&self, attempts: u32) {
+				ACQUIRED.store(attempts, Relaxed);
+			}
+		}
+
+		let lock = RwLock::with_policy(0u32, Recording);
+		let w = lock.write();
+		std::thread::scope(|s| {
+			let reader = s.spawn(|| *lock.read());
+			// SPIN_ATTEMPTS回待ってから、futexで寝る
+			while lock.waiting_readers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			assert_eq!(*ATTEMPTS.lock().unwrap(), (0..SPIN_ATTEMPTS).collect::<Vec<_>>());
+			drop(w);
+			assert_eq!(reader.join().unwrap(), 0);
+		});
+		assert_eq!(ACQUIRED.load(Relaxed), SPIN_ATTEMPTS);
+
+		// writerも同じように待つ。取れた値は正しい
+		ATTEMPTS.lock().unwrap().clear();
+		let r = lock.read();
+		std::thread::scope(|s| {
+			let writer = s.spawn(|| *lock.write() += 1);
+			while lock.waiting_writers.load(Relaxed) == 0 {
+				std::thread::yield_now();
+			}
+			assert_eq!(*ATTEMPTS.lock().unwrap(), (0..SPIN_ATTEMPTS).collect::<Vec<_>>());
+			drop(r);
+			writer.join().unwrap();
+		});
+		assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn try_upgrade_is_gap_free() {
+		use std::sync::atomic::AtomicBool;
+		use std::time::Duration;
+
+		let lock = RwLock::new(0);
+		let writer_won = AtomicBool::new(false);
+		let r = lock.read();
+		let other = lock.read();
+		// readerが2つなら失敗してreaderのまま戻る
+		let Err(r) = r.try_upgrade() else { panic!("upgraded with two readers") };
+		drop(other);
+
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				let mut w = lock.write();
+				writer_won.store(true, Relaxed);
+				// upgradeした側の書き込みの後に取れている
+				assert_eq!(*w, 1);
+				*w = 2;
+			});
+			// writerが待機中ビットを立てるまで待つ
+			while lock.state.load(Relaxed) != 3 {
+				std::thread::yield_now();
+			}
+			let Ok(mut w) = r.try_upgrade() else { panic!("sole reader failed to upgrade") };
+			assert_eq!(lock.state.load(Relaxed), u32::MAX);
+			std::thread::sleep(Duration::from_millis(50));
+			assert!(!writer_won.load(Relaxed));
+			*w = 1;
+		});
+		assert!(writer_won.load(Relaxed));
+		assert_eq!(*lock.read(), 2);
+    }
 }
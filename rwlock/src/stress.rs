@@ -0,0 +1,72 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+
+use xorshift::XorShift;
+
+use crate::RwLock;
+
+// writerは2つの値を同じだけ増やし、readerは2つが常に等しいことを確かめる
+// 最後に書き込み回数と値が一致するかも調べる
+pub fn stress_rwlock(threads: usize, iters: usize) -> Result<(), String> {
+	let lock = RwLock::new((0u64, 0u64));
+	let writes = AtomicU64::new(0);
+
+	thread::scope(|s| {
+		let handles: Vec<_> = (0..threads)
+			.map(|_| {
+				s.spawn(|| -> Result<(), String> {
+					let mut rng = XorShift::new();
+					for _ in 0..iters {
+						match rng.next_u64() % 3 {
+							0 => {
+								let mut g = lock.write();
+								g.0 += 1;
+								thread::yield_now();
+								g.1 += 1;
+								writes.fetch_add(1, Relaxed);
+							}
+							1 => {
+								let g = lock.read();
+								if g.0 != g.1 {
+									return Err(format!("torn read: {:?}", *g));
+								}
+								if let Ok(mut w) = g.try_upgrade() {
+									w.0 += 1;
+									w.1 += 1;
+									writes.fetch_add(1, Relaxed);
+								}
+							}
+							_ => {
+								let g = lock.read();
+								thread::yield_now();
+								if g.0 != g.1 {
+									return Err(format!("torn read: {:?}", *g));
+								}
+							}
+						}
+					}
+					Ok(())
+				})
+			})
+			.collect();
+		handles.into_iter().try_for_each(|h| h.join().map_err(|_| "thread panicked".to_string())?)
+	})?;
+
+	let g = lock.read();
+	let expected = writes.load(Relaxed);
+	if *g != (expected, expected) {
+		return Err(format!("final value {:?}, expected {expected} writes", *g));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn smoke() {
+		assert_eq!(stress_rwlock(4, 500), Ok(()));
+	}
+}
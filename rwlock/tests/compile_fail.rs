@@ -0,0 +1,6 @@
+// コンパイルできてはいけないガードの使い方が、誤って通らないことを確かめる
+#[test]
+fn compile_fail() {
+	let t = trybuild::TestCases::new();
+	t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,8 @@
+use rwlock::MappedWriteGuard;
+
+// &'static strのガードを短い&strのガードとして扱えると、dangleする&strを書き込めてしまう
+fn shorten<'g, 'a, T>(g: MappedWriteGuard<'g, T, &'static str>) -> MappedWriteGuard<'g, T, &'a str> {
+	g
+}
+
+fn main() {}
@@ -0,0 +1,172 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+use atomic_wait::{wait, wake_one};
+use condvar::Condvar;
+use mutex::Mutex;
+
+// 整理券方式。next_ticketを取った順にservingが回ってくる
+struct Fair {
+	permits: u32,
+	next_ticket: u64,
+	serving: u64,
+}
+
+enum Inner {
+	// 空いた許可は起きたスレッドと新しく来たスレッドの早い者勝ち
+	Unfair(AtomicU32),
+	// 待っている順に許可を渡す。後から来たacquireは追い越せない
+	Fair { state: Mutex<Fair>, turn: Condvar },
+}
+
+pub struct Semaphore {
+	inner: Inner,
+}
+
+impl Semaphore {
+	pub const fn new(permits: u32) -> Self {
+		Self { inner: Inner::Unfair(AtomicU32::new(permits)) }
+	}
+
+	pub fn new_fair(permits: u32) -> Self {
+		Self {
+			inner: Inner::Fair {
+				state: Mutex::new(Fair { permits, next_ticket: 0, serving: 0 }),
+				turn: Condvar::new(),
+			},
+		}
+	}
+
+	pub fn acquire(&self) -> Permit<'_> {
+		match &self.inner {
+			Inner::Unfair(permits) => {
+				let mut p = permits.load(Relaxed);
+				loop {
+					if p == 0 {
+						wait(permits, 0);
+						p = permits.load(Relaxed);
+						continue;
+					}
+					match permits.compare_exchange_weak(p, p - 1, Acquire, Relaxed) {
+						Ok(_) => break,
+						Err(e) => p = e,
+					}
+				}
+			}
+			Inner::Fair { state, turn } => {
+				let mut s = state.lock();
+				let ticket = s.next_ticket;
+				s.next_ticket += 1;
+				while s.serving != ticket || s.permits == 0 {
+					s = turn.wait(s);
+				}
+				s.permits -= 1;
+				s.serving += 1;
+				// 次の番のスレッドも許可が残っていれば進める
+				turn.notify_all();
+			}
+		}
+		Permit { semaphore: self }
+	}
+
+	// 許可がなければ待たずにNoneを返す。公平モードでは待っているスレッドがいても失敗する
+	pub fn try_acquire(&self) -> Option<Permit<'_>> {
+		match &self.inner {
+			Inner::Unfair(permits) => {
+				permits.fetch_update(Acquire, Relaxed, |p| p.checked_sub(1)).ok()?;
+			}
+			Inner::Fair { state, .. } => {
+				let mut s = state.lock();
+				if s.permits == 0 || s.serving != s.next_ticket {
+					return None;
+				}
+				s.permits -= 1;
+			}
+		}
+		Some(Permit { semaphore: self })
+	}
+
+	pub fn available_permits(&self) -> u32 {
+		match &self.inner {
+			Inner::Unfair(permits) => permits.load(Relaxed),
+			Inner::Fair { state, .. } => state.lock().permits,
+		}
+	}
+
+	fn release(&self) {
+		match &self.inner {
+			Inner::Unfair(permits) => {
+				permits.fetch_add(1, Release);
+				wake_one(permits);
+			}
+			Inner::Fair { state, turn } => {
+				state.lock().permits += 1;
+				// 先頭のスレッドがどれか分からないので全員起こす
+				turn.notify_all();
+			}
+		}
+	}
+}
+
+// dropで許可を返す
+pub struct Permit<'a> {
+	semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+	fn drop(&mut self) {
+		self.semaphore.release();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::AtomicUsize;
+	use std::thread;
+	use std::time::Duration;
+
+	use super::*;
+
+	#[test]
+	fn limits_concurrency() {
+		for sem in [Semaphore::new(2), Semaphore::new_fair(2)] {
+			let inside = AtomicUsize::new(0);
+			thread::scope(|s| {
+				for _ in 0..6 {
+					s.spawn(|| {
+						let _p = sem.acquire();
+						assert!(inside.fetch_add(1, Relaxed) < 2);
+						thread::sleep(Duration::from_millis(5));
+						inside.fetch_sub(1, Relaxed);
+					});
+				}
+			});
+			assert_eq!(sem.available_permits(), 2);
+		}
+	}
+
+	#[test]
+	fn fair_hands_permit_to_longest_waiter() {
+		let sem = Semaphore::new_fair(1);
+		let order = Mutex::new(Vec::new());
+		let permit = sem.acquire();
+		thread::scope(|s| {
+			s.spawn(|| {
+				let _p = sem.acquire();
+				order.lock().push("waiter");
+				thread::sleep(Duration::from_millis(20));
+			});
+			// waiterが整理券を取るまで待つ
+			let Inner::Fair { state, .. } = &sem.inner else { unreachable!() };
+			while state.lock().next_ticket < 2 {
+				thread::yield_now();
+			}
+			drop(permit);
+			// 今来たacquireは、先に待っていたwaiterを追い越せない
+			assert!(sem.try_acquire().is_none());
+			let _p = sem.acquire();
+			order.lock().push("newcomer");
+		});
+		assert_eq!(*order.lock(), ["waiter", "newcomer"]);
+	}
+}
@@ -0,0 +1,199 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// リストの終わり
+const NIL: u32 = u32::MAX;
+
+struct Slot<T> {
+  // 同じリストで次につながっているスロット
+  next: AtomicU32,
+  value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// ヒープを使わない固定長のスタック。staticに置ける
+// 値の入ったスロットのリストと空いているスロットのリストを持ち、どちらも先頭のCASだけで付け外しする
+// ロックフリー。途中で止められたスレッドがいても、他のスレッドのCASは失敗し続けない
+pub struct BoundedStack<T, const N: usize> {
+  // 上位32ビットがタグ、下位32ビットが先頭のスロット。付け外しのたびにタグを増やすので、
+  // 一度外されて付け直された同じスロットへのCASは失敗する(ABA)
+  head: AtomicU64,
+  free: AtomicU64,
+  // 目安の要素数。push中の要素も数える
+  len: AtomicUsize,
+  slots: [Slot<T>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Sync for BoundedStack<T, N> {}
+
+// タグを1つ進めて、先頭をiにする
+fn tagged(head: u64, i: u32) -> u64 {
+  (head & !(NIL as u64)).wrapping_add(1 << 32) | i as u64
+}
+
+impl<T, const N: usize> BoundedStack<T, N> {
+  pub const fn new() -> Self {
+    const { assert!(N < NIL as usize, "BoundedStack: N is too large") };
+    let mut slots = [const { Slot { next: AtomicU32::new(NIL), value: UnsafeCell::new(MaybeUninit::uninit()) } }; N];
+    // 最初は全てのスロットが空きリストに並んでいる
+    let mut i = 0;
+    while i + 1 < N {
+      slots[i].next = AtomicU32::new(i as u32 + 1);
+      i += 1;
+    }
+    Self {
+      head: AtomicU64::new(NIL as u64),
+      free: AtomicU64::new(if N == 0 { NIL as u64 } else { 0 }),
+      len: AtomicUsize::new(0),
+      slots,
+    }
+  }
+
+  // いっぱいなら値を返す
+  pub fn push(&self, value: T) -> Result<(), T> {
+    let Some(i) = self.take(&self.free) else {
+      return Err(value);
+    };
+    // 空きリストから外したスロットは、headに付けるまで自分しか触らない
+    unsafe { (*self.slots[i as usize].value.get()).write(value) };
+    self.len.fetch_add(1, Relaxed);
+    self.put(&self.head, i);
+    Ok(())
+  }
+
+  pub fn pop(&self) -> Option<T> {
+    let i = self.take(&self.head)?;
+    let value = unsafe { (*self.slots[i as usize].value.get()).assume_init_read() };
+    self.len.fetch_sub(1, Relaxed);
+    self.put(&self.free, i);
+    Some(value)
+  }
+
+  // 目安。書き込み途中の要素も数える
+  pub fn len(&self) -> usize {
+    self.len.load(Relaxed)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // listの先頭のスロットを外す。空ならNone
+  fn take(&self, list: &AtomicU64) -> Option<u32> {
+    let mut head = list.load(Acquire);
+    loop {
+      let i = head as u32;
+      if i == NIL {
+        return None;
+      }
+      // 読んだ後にiが外されて付け直されていても、タグが変わっているのでCASは失敗する
+      let next = self.slots[i as usize].next.load(Relaxed);
+      match list.compare_exchange_weak(head, tagged(head, next), Acquire, Acquire) {
+        Ok(_) => return Some(i),
+        Err(h) => head = h,
+      }
+    }
+  }
+
+  // 外したスロットiをlistの先頭に付ける。Releaseで、それまでのvalueの読み書きを次に外す相手に見せる
+  fn put(&self, list: &AtomicU64, i: u32) {
+    let mut head = list.load(Relaxed);
+    loop {
+      self.slots[i as usize].next.store(head as u32, Relaxed);
+      match list.compare_exchange_weak(head, tagged(head, i), Release, Relaxed) {
+        Ok(_) => return,
+        Err(h) => head = h,
+      }
+    }
+  }
+}
+
+impl<T, const N: usize> Default for BoundedStack<T, N> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T, const N: usize> Drop for BoundedStack<T, N> {
+  fn drop(&mut self) {
+    // 値が入っているのはheadからたどれるスロットだけ
+    let mut i = *self.head.get_mut() as u32;
+    while i != NIL {
+      let slot = &mut self.slots[i as usize];
+      unsafe { slot.value.get_mut().assume_init_drop() };
+      i = *slot.next.get_mut();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::thread;
+
+  use super::*;
+
+  static STACK: BoundedStack<usize, 16> = BoundedStack::new();
+
+  #[test]
+  fn full_and_empty() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Counted(&'static str);
+    impl Drop for Counted {
+      fn drop(&mut self) {
+        DROPS.fetch_add(1, Relaxed);
+      }
+    }
+
+    let s: BoundedStack<Counted, 3> = BoundedStack::new();
+    for v in ["a", "b", "c"] {
+      assert!(s.push(Counted(v)).is_ok());
+    }
+    // いっぱいなら値がそのまま返ってくる
+    assert_eq!(s.push(Counted("d")).err().map(|c| c.0), Some("d"));
+    assert_eq!(s.len(), 3);
+    for v in ["c", "b", "a"] {
+      assert_eq!(s.pop().map(|c| c.0), Some(v));
+    }
+    assert!(s.pop().is_none());
+    assert!(s.is_empty());
+    assert_eq!(DROPS.load(Relaxed), 4);
+    // 残っている要素はスタックと一緒にちょうど1回ずつdropされる
+    assert!(s.push(Counted("e")).is_ok());
+    assert!(s.push(Counted("f")).is_ok());
+    drop(s);
+    assert_eq!(DROPS.load(Relaxed), 6);
+  }
+
+  #[test]
+  fn concurrent_push_pop() {
+    const THREADS: usize = 4;
+    const PER_THREAD: usize = 1000;
+    let sum = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+      for t in 0..THREADS {
+        let sum = &sum;
+        s.spawn(move || {
+          for i in 0..PER_THREAD {
+            let mut v = t * PER_THREAD + i;
+            while let Err(back) = STACK.push(v) {
+              v = back;
+              thread::yield_now();
+            }
+            if let Some(v) = STACK.pop() {
+              sum.fetch_add(v, Relaxed);
+            }
+          }
+        });
+      }
+    });
+    while let Some(v) = STACK.pop() {
+      sum.fetch_add(v, Relaxed);
+    }
+    // 全ての値がちょうど1回ずつ取り出された
+    let n = THREADS * PER_THREAD;
+    assert_eq!(sum.load(Relaxed), n * (n - 1) / 2);
+  }
+}
@@ -2,29 +2,135 @@ use std::cell::UnsafeCell;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Acquire, Release};
+use std::time::{Duration, Instant};
 
-pub struct SpinLock<T> {
+mod bounded_stack;
+mod lru_cache;
+mod rcu;
+mod spin_mpsc;
+mod tiny_lock;
+
+pub use bounded_stack::BoundedStack;
+pub use lru_cache::LruCache;
+pub use rcu::{Rcu, RcuReadGuard};
+pub use retry_policy::{Adaptive, ExponentialCapped, RetryPolicy, Spin, YieldAfter};
+pub use spin_mpsc::SpinMpsc;
+pub use tiny_lock::{TinyLock, TinyReadGuard, TinyWriteGuard, TINY_MAX_READERS};
+
+pub struct SpinLock<T, P = Spin> {
   locked:AtomicBool,
+  // ロックが取れなかったときの待ち方
+  policy: P,
+  // (閾値, コールバック)。閾値より長くロックを持っていたらdrop時に呼ぶ
+  #[cfg(feature = "hold-timing")]
+  long_hold: Option<(Duration, fn(Duration))>,
   value: UnsafeCell<T>,
 }
 
 // Tに対して１つのスレッドがアクセスすることを保証する
-unsafe impl<T> Sync for SpinLock<T> where T:Send {}
+unsafe impl<T, P> Sync for SpinLock<T, P> where T:Send, P: Sync {}
 
 impl<T> SpinLock<T> {
   pub const fn new(value: T) -> Self {
+    Self::with_policy(value, Spin)
+  }
+
+  // ロックの保持時間がthresholdを超えたらhookに保持時間を渡す
+  #[cfg(feature = "hold-timing")]
+  pub const fn with_long_hold_hook(value: T, threshold: Duration, hook: fn(Duration)) -> Self {
     Self {
       locked: AtomicBool::new(false),
+      policy: Spin,
+      long_hold: Some((threshold, hook)),
       value: UnsafeCell::new(value),
     }
   }
+}
+
+impl<T> SpinLock<T, Adaptive> {
+  // 最近の競合具合からバックオフを調整するSpinLock
+  pub const fn new_adaptive(value: T) -> Self {
+    Self::with_policy(value, Adaptive::new())
+  }
+
+  // 最近ロックを取るまでに失敗した平均回数
+  pub fn contention(&self) -> u32 {
+    self.policy.contention()
+  }
+}
+
+impl<T, P: RetryPolicy> SpinLock<T, P> {
+  pub const fn with_policy(value: T, policy: P) -> Self {
+    Self {
+      locked: AtomicBool::new(false),
+      policy,
+      #[cfg(feature = "hold-timing")]
+      long_hold: None,
+      value: UnsafeCell::new(value),
+    }
+  }
+
+  pub fn lock(&self) -> Guard<'_, T, P> {
+    let mut attempt = 0;
+    while self.locked.swap(true, Acquire) {
+      self.policy.backoff(attempt);
+      attempt = attempt.saturating_add(1);
+    }
+    self.policy.acquired(attempt);
+
+    Guard {
+      lock: self,
+      #[cfg(feature = "hold-timing")]
+      acquired: Instant::now(),
+    }
+  }
+
+  // ロックを取ってからpredで値を調べる。falseならすぐに解放してNone、trueならガードを持ったまま返す
+  pub fn lock_if(&self, pred: impl FnOnce(&T) -> bool) -> Option<Guard<'_, T, P>> {
+    let guard = self.lock();
+    // falseならここでguardがdropされて解放される
+    pred(&guard).then_some(guard)
+  }
+
+  // ロックを取らずに、空くまでスピンして待つ。Acquireで、解放したスレッドの書き込みが見える
+  // 戻った直後に他のスレッドがまた取っているかもしれない。空いていることは保証しない
+  pub fn wait_unlocked(&self) {
+    while self.locked.load(Acquire) {
+      std::hint::spin_loop();
+    }
+  }
 
-  pub fn lock(&self) -> Guard<T> {
+  // durの間スピンしても取れなければNone。Instant::nowは重いので、SPINS_PER_CLOCK回に1回だけ読む
+  pub fn try_lock_for(&self, dur: Duration) -> Option<Guard<'_, T, P>> {
+    const SPINS_PER_CLOCK: u32 = 64;
+    let deadline = Instant::now() + dur;
+    let mut spins = 0;
     while self.locked.swap(true, Acquire) {
+      spins += 1;
+      if spins % SPINS_PER_CLOCK == 0 && Instant::now() >= deadline {
+        return None;
+      }
       std::hint::spin_loop();
     }
 
-    Guard { lock: self }
+    Some(Guard {
+      lock: self,
+      #[cfg(feature = "hold-timing")]
+      acquired: Instant::now(),
+    })
+  }
+
+  // ロックを取ったまま古い値から新しい値と戻り値を計算し、新しい値を書き込む
+  pub fn fetch_update<R>(&self, f: impl FnOnce(&T) -> (T, R)) -> R {
+    let mut guard = self.lock();
+    let (new, result) = f(&guard);
+    *guard = new;
+    result
+  }
+
+  // 中身をそのまま新しいRwLockに移す。selfを取るのでガードは残っていない
+  pub fn into_rwlock(self) -> rwlock::RwLock<T> {
+    rwlock::RwLock::new(self.value.into_inner())
   }
 
   pub fn unlock(&self) {
@@ -33,14 +139,23 @@ impl<T> SpinLock<T> {
 }
 
 // Guardが存在することでlockされてることを保証する
-pub struct Guard<'a, T> {
-  lock: &'a SpinLock<T>,
+pub struct Guard<'a, T, P = Spin> {
+  lock: &'a SpinLock<T, P>,
+  #[cfg(feature = "hold-timing")]
+  acquired: Instant,
 }
 
-unsafe impl<T> Send for Guard<'_, T> where T: Send {}
-unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+unsafe impl<T, P> Send for Guard<'_, T, P> where T: Send, P: Sync {}
+unsafe impl<T, P> Sync for Guard<'_, T, P> where T: Sync, P: Sync {}
+
+impl<T, P> Guard<'_, T, P> {
+  // 取ったガードを渡してfの間だけ持ち、終わったら解放する。fがpanicしてもガードのdropで解放される
+  pub fn scope<R>(mut self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self)
+  }
+}
 
-impl<T> Deref for Guard<'_, T> {
+impl<T, P> Deref for Guard<'_, T, P> {
   type Target = T;
 
   fn deref(&self) -> &Self::Target {
@@ -48,15 +163,24 @@ impl<T> Deref for Guard<'_, T> {
   }
 }
 
-impl<T> DerefMut for Guard<'_, T> {
+impl<T, P> DerefMut for Guard<'_, T, P> {
   fn deref_mut(&mut self) -> &mut Self::Target {
     unsafe { &mut *self.lock.value.get() }
   }
 }
 
-impl<T> Drop for Guard<'_, T> {
+impl<T, P> Drop for Guard<'_, T, P> {
   fn drop(&mut self) {
+    #[cfg(feature = "hold-timing")]
+    let held = self.acquired.elapsed();
     self.lock.locked.store(false, Release);
+    // スピンで待っているスレッドを止めないように、解放してから呼ぶ
+    #[cfg(feature = "hold-timing")]
+    if let Some((threshold, hook)) = self.lock.long_hold {
+      if held > threshold {
+        hook(held);
+      }
+    }
   }
 }
 
@@ -82,4 +206,237 @@ mod tests {
       assert_eq!(*g, 1000);
     }
 
+    #[test]
+    fn test_fetch_update() {
+      #[derive(Debug, Clone, Copy, PartialEq)]
+      enum State {
+        Idle,
+        Running(u32),
+        Done,
+      }
+
+      let l = SpinLock::new(State::Idle);
+      let step = |s: &State| {
+        let next = match *s {
+          State::Idle => State::Running(0),
+          State::Running(n) if n < 2 => State::Running(n + 1),
+          State::Running(_) | State::Done => State::Done,
+        };
+        (next, *s)
+      };
+      assert_eq!(l.fetch_update(step), State::Idle);
+      assert_eq!(l.fetch_update(step), State::Running(0));
+      assert_eq!(l.fetch_update(step), State::Running(1));
+      assert_eq!(l.fetch_update(step), State::Running(2));
+      assert_eq!(*l.lock(), State::Done);
+    }
+
+    #[test]
+    fn test_custom_retry_policy() {
+      use std::cell::Cell;
+      use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+      thread_local! {
+        static LAST: Cell<Option<u32>> = const { Cell::new(None) };
+      }
+
+      // attemptが0から1ずつ増えているかを数える
+      struct Recording {
+        calls: AtomicU32,
+        out_of_order: AtomicU32,
+      }
+
+      impl RetryPolicy for Recording {
+        fn backoff(&self, attempt: u32) {
+          self.calls.fetch_add(1, Relaxed);
+          let expected = LAST.get().map_or(0, |last| last + 1);
+          if attempt != 0 && attempt != expected {
+            self.out_of_order.fetch_add(1, Relaxed);
+          }
+          LAST.set(Some(attempt));
+          thread::yield_now();
+        }
+      }
+
+      let l = SpinLock::with_policy(0, Recording {
+        calls: AtomicU32::new(0),
+        out_of_order: AtomicU32::new(0),
+      });
+      thread::scope(|s| {
+        let g = l.lock();
+        for _ in 0..4 {
+          s.spawn(|| {
+            for _ in 0..100 {
+              *l.lock() += 1;
+            }
+          });
+        }
+        // 他のスレッドを確実に待たせる
+        thread::sleep(std::time::Duration::from_millis(20));
+        drop(g);
+      });
+      assert_eq!(*l.lock(), 400);
+      assert!(l.policy.calls.load(Relaxed) > 0);
+      assert_eq!(l.policy.out_of_order.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn test_try_lock_for() {
+      use std::time::{Duration, Instant};
+
+      let l = SpinLock::new(0);
+      let g = l.lock();
+      thread::scope(|s| {
+        s.spawn(|| {
+          let start = Instant::now();
+          assert!(l.try_lock_for(Duration::from_millis(20)).is_none());
+          assert!(start.elapsed() >= Duration::from_millis(20));
+        });
+      });
+      drop(g);
+      *l.try_lock_for(Duration::from_millis(20)).unwrap() += 1;
+      assert_eq!(*l.lock(), 1);
+    }
+
+    #[test]
+    fn test_lock_if() {
+      let l = SpinLock::new(0);
+      assert!(l.lock_if(|v| *v > 0).is_none());
+      // Noneのときは解放されている
+      assert!(!l.locked.load(Acquire));
+
+      *l.lock() = 1;
+      let mut g = l.lock_if(|v| *v > 0).unwrap();
+      assert!(l.locked.load(Acquire));
+      *g += 1;
+      drop(g);
+      assert_eq!(*l.lock(), 2);
+    }
+
+    #[test]
+    fn test_wait_unlocked() {
+      use std::sync::atomic::Ordering::Relaxed;
+      let l = SpinLock::new(0);
+      let released = AtomicBool::new(false);
+      let mut g = l.lock();
+      thread::scope(|s| {
+        s.spawn(|| {
+          l.wait_unlocked();
+          assert!(released.load(Relaxed));
+          // 取らずに待っただけなので、そのまま取れる
+          assert_eq!(*l.lock(), 1);
+        });
+        thread::sleep(Duration::from_millis(10));
+        *g = 1;
+        released.store(true, Relaxed);
+        drop(g);
+      });
+    }
+
+    #[test]
+    fn test_guard_scope() {
+      let l = SpinLock::new(vec![1]);
+      // 空いているので待たずに取れる
+      let g = l.try_lock_for(Duration::ZERO).unwrap();
+      let len = g.scope(|v| {
+        v.push(2);
+        v.len()
+      });
+      assert_eq!(len, 2);
+      // scopeを抜けたら解放されている
+      assert!(!l.locked.load(Acquire));
+      assert_eq!(*l.lock(), [1, 2]);
+    }
+
+    #[test]
+    fn test_builtin_policies() {
+      fn count<P: RetryPolicy + Sync>(l: SpinLock<u32, P>) {
+        thread::scope(|s| {
+          for _ in 0..4 {
+            s.spawn(|| {
+              for _ in 0..100 {
+                *l.lock() += 1;
+              }
+            });
+          }
+        });
+        assert_eq!(*l.lock(), 400);
+      }
+      count(SpinLock::with_policy(0, YieldAfter(10)));
+      count(SpinLock::with_policy(0, ExponentialCapped { cap: 64 }));
+    }
+
+    #[test]
+    fn test_adaptive_tracks_contention() {
+      let l = SpinLock::new_adaptive(0u32);
+      // 競合が無ければ平均は0のまま
+      for _ in 0..100 {
+        *l.lock() += 1;
+      }
+      assert_eq!(l.contention(), 0);
+
+      // ロックを持ったままyieldして、他のスレッドに必ず待たせる
+      use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+      let peak = AtomicU32::new(0);
+      thread::scope(|s| {
+        for _ in 0..4 {
+          s.spawn(|| {
+            for _ in 0..100 {
+              let mut g = l.lock();
+              *g += 1;
+              peak.fetch_max(l.contention(), Relaxed);
+              thread::yield_now();
+            }
+          });
+        }
+      });
+      assert_eq!(*l.lock(), 500);
+      assert!(peak.load(Relaxed) > 0);
+
+      // 競合が無くなれば0まで戻る
+      for _ in 0..100 {
+        *l.lock() += 1;
+      }
+      assert_eq!(l.contention(), 0);
+    }
+
+    #[test]
+    fn test_into_rwlock() {
+      let l = SpinLock::new(vec![1]);
+      l.lock().push(2);
+      let rw = l.into_rwlock();
+      thread::scope(|s| {
+        for _ in 0..4 {
+          s.spawn(|| assert_eq!(*rw.read(), [1, 2]));
+        }
+      });
+      rw.write().push(3);
+      assert_eq!(*rw.read(), [1, 2, 3]);
+    }
+
+    #[cfg(feature = "hold-timing")]
+    #[test]
+    fn test_long_hold_hook() {
+      use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+      use std::time::Duration;
+
+      static HELD_MS: AtomicU64 = AtomicU64::new(0);
+      fn hook(d: Duration) {
+        HELD_MS.store(d.as_millis() as u64, Relaxed);
+      }
+
+      let l = SpinLock::with_long_hold_hook(0, Duration::from_millis(10), hook);
+      // 閾値未満なら呼ばれない
+      *l.lock() += 1;
+      assert_eq!(HELD_MS.load(Relaxed), 0);
+
+      {
+        let mut g = l.lock();
+        *g += 1;
+        thread::sleep(Duration::from_millis(50));
+      }
+      let held = HELD_MS.load(Relaxed);
+      assert!((50..1000).contains(&held), "held {held}ms");
+    }
+
 }
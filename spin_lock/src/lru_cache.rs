@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use crate::SpinLock;
+
+struct Lru<K, V> {
+  // 値と最後に使った時刻
+  entries: HashMap<K, (V, u64)>,
+  // 時刻 -> キー。先頭が一番古い
+  order: BTreeMap<u64, K>,
+  tick: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> Lru<K, V> {
+  // keyを最新にする。keyが無ければ何もしない
+  fn touch(&mut self, key: &K) -> Option<&V> {
+    let tick = self.tick;
+    let (value, used) = self.entries.get_mut(key)?;
+    let k = self.order.remove(used).unwrap();
+    *used = tick;
+    self.order.insert(tick, k);
+    self.tick += 1;
+    Some(value)
+  }
+}
+
+// 容量を超えたら最も長く使われていないものを捨てるキャッシュ。操作ごとに短い間だけSpinLockを持つ
+pub struct LruCache<K, V> {
+  inner: SpinLock<Lru<K, V>>,
+  capacity: usize,
+}
+
+impl<K: Eq + Hash + Clone, V> LruCache<K, V> {
+  pub fn new(capacity: usize) -> Self {
+    assert!(capacity > 0, "LruCache capacity must be positive");
+    Self {
+      inner: SpinLock::new(Lru { entries: HashMap::new(), order: BTreeMap::new(), tick: 0 }),
+      capacity,
+    }
+  }
+
+  // 見つかればcloneして返し、最近使ったことにする
+  pub fn get(&self, key: &K) -> Option<V>
+  where
+    V: Clone,
+  {
+    self.inner.lock().touch(key).cloned()
+  }
+
+  pub fn put(&self, key: K, value: V) {
+    // 追い出した値はロックの外でdropする
+    let evicted;
+    {
+      let mut lru = self.inner.lock();
+      let tick = lru.tick;
+      lru.tick += 1;
+      if let Some((old, used)) = lru.entries.insert(key.clone(), (value, tick)) {
+        lru.order.remove(&used);
+        lru.order.insert(tick, key);
+        evicted = Some(old);
+      } else {
+        lru.order.insert(tick, key);
+        evicted = if lru.entries.len() > self.capacity {
+          let (_, oldest) = lru.order.pop_first().unwrap();
+          lru.entries.remove(&oldest).map(|(v, _)| v)
+        } else {
+          None
+        };
+      }
+    }
+    drop(evicted);
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.lock().entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let cache = LruCache::new(2);
+    cache.put("a", 1);
+    cache.put("b", 2);
+    // aを使ったので、次に追い出されるのはb
+    assert_eq!(cache.get(&"a"), Some(1));
+    cache.put("c", 3);
+    assert_eq!(cache.get(&"b"), None);
+    assert_eq!(cache.get(&"a"), Some(1));
+    assert_eq!(cache.get(&"c"), Some(3));
+
+    // 上書きも最近使ったことになる
+    cache.put("a", 10);
+    cache.put("d", 4);
+    assert_eq!(cache.get(&"c"), None);
+    assert_eq!(cache.get(&"a"), Some(10));
+    assert_eq!(cache.len(), 2);
+  }
+
+  #[test]
+  fn concurrent_get_put_stays_bounded() {
+    let cache = LruCache::new(8);
+    thread::scope(|s| {
+      for t in 0..4u32 {
+        let cache = &cache;
+        s.spawn(move || {
+          for i in 0..1000u32 {
+            let key = (i * 7 + t) % 32;
+            cache.put(key, key * 2);
+            if let Some(v) = cache.get(&((i + t) % 32)) {
+              assert_eq!(v, ((i + t) % 32) * 2);
+            }
+            assert!(cache.len() <= 8);
+          }
+        });
+      }
+    });
+    let lru = cache.inner.lock();
+    assert_eq!(lru.entries.len(), 8);
+    assert_eq!(lru.order.len(), 8);
+    assert!(lru.order.iter().all(|(tick, k)| lru.entries[k].1 == *tick));
+  }
+}
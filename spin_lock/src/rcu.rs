@@ -0,0 +1,93 @@
+use std::ops::Deref;
+
+use arc::Arc;
+
+use crate::SpinLock;
+
+// 読み込みが多く更新が少ないデータ向け。readerは今の版のArcを1つcloneするだけ
+// 古い版は、それを指すRcuReadGuardがすべてdropされたときに解放される
+pub struct Rcu<T> {
+  current: SpinLock<Arc<T>>,
+  // updateどうしを直列にする。fの実行中もreaderは止まらない
+  writer: SpinLock<()>,
+}
+
+impl<T> Rcu<T> {
+  pub fn new(value: T) -> Self {
+    Self {
+      current: SpinLock::new(Arc::new(value)),
+      writer: SpinLock::new(()),
+    }
+  }
+
+  pub fn read(&self) -> RcuReadGuard<T> {
+    RcuReadGuard { version: self.current.lock().clone() }
+  }
+
+  // 今の版からfで新しい版を作って公開する。既にreadしたガードは古い版を見続ける
+  pub fn update(&self, f: impl FnOnce(&T) -> T) {
+    let _writer = self.writer.lock();
+    let old = self.current.lock().clone();
+    let new = Arc::new(f(&old));
+    let replaced = std::mem::replace(&mut *self.current.lock(), new);
+    // 古い版のdropはロックの外で行う
+    drop(replaced);
+  }
+}
+
+pub struct RcuReadGuard<T> {
+  version: Arc<T>,
+}
+
+impl<T> Deref for RcuReadGuard<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.version
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering::Relaxed;
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn old_reader_keeps_old_version() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Version(u32);
+    impl Drop for Version {
+      fn drop(&mut self) {
+        DROPS.fetch_add(1, Relaxed);
+      }
+    }
+
+    let rcu = Rcu::new(Version(1));
+    let old = rcu.read();
+    thread::scope(|s| {
+      s.spawn(|| rcu.update(|v| Version(v.0 + 1)));
+    });
+    assert_eq!(old.0, 1);
+    assert_eq!(rcu.read().0, 2);
+    // 古い版はまだoldが持っている
+    assert_eq!(DROPS.load(Relaxed), 0);
+    drop(old);
+    assert_eq!(DROPS.load(Relaxed), 1);
+
+    thread::scope(|s| {
+      for _ in 0..4 {
+        s.spawn(|| {
+          for _ in 0..10 {
+            rcu.update(|v| Version(v.0 + 1));
+            assert!(rcu.read().0 >= 2);
+          }
+        });
+      }
+    });
+    assert_eq!(rcu.read().0, 42);
+    assert_eq!(DROPS.load(Relaxed), 41);
+  }
+}
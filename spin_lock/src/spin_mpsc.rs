@@ -0,0 +1,129 @@
+use std::mem::MaybeUninit;
+
+use crate::SpinLock;
+
+struct Ring<T, const N: usize> {
+  buf: [MaybeUninit<T>; N],
+  // 先頭の要素の位置
+  head: usize,
+  len: usize,
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+  fn drop(&mut self) {
+    for i in 0..self.len {
+      unsafe { self.buf[(self.head + i) % N].assume_init_drop(); }
+    }
+  }
+}
+
+// ヒープを使わない固定長のキュー。staticに置ける
+// producerもconsumerも短い間だけSpinLockを持つ
+pub struct SpinMpsc<T, const N: usize> {
+  ring: SpinLock<Ring<T, N>>,
+}
+
+impl<T, const N: usize> SpinMpsc<T, N> {
+  pub const fn new() -> Self {
+    Self {
+      ring: SpinLock::new(Ring {
+        buf: [const { MaybeUninit::uninit() }; N],
+        head: 0,
+        len: 0,
+      }),
+    }
+  }
+
+  // いっぱいなら値を返す
+  pub fn try_push(&self, value: T) -> Result<(), T> {
+    let mut ring = self.ring.lock();
+    if ring.len == N {
+      return Err(value);
+    }
+    let tail = (ring.head + ring.len) % N;
+    ring.buf[tail].write(value);
+    ring.len += 1;
+    Ok(())
+  }
+
+  pub fn pop(&self) -> Option<T> {
+    let mut ring = self.ring.lock();
+    if ring.len == 0 {
+      return None;
+    }
+    let head = ring.head;
+    let value = unsafe { ring.buf[head].assume_init_read() };
+    ring.head = (head + 1) % N;
+    ring.len -= 1;
+    Some(value)
+  }
+
+  pub fn len(&self) -> usize {
+    self.ring.lock().len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<T, const N: usize> Default for SpinMpsc<T, N> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  static QUEUE: SpinMpsc<(usize, usize), 8> = SpinMpsc::new();
+
+  #[test]
+  fn multiple_producers_one_consumer() {
+    const PRODUCERS: usize = 4;
+    const PER_PRODUCER: usize = 1000;
+
+    thread::scope(|s| {
+      for id in 0..PRODUCERS {
+        s.spawn(move || {
+          for seq in 0..PER_PRODUCER {
+            let mut item = (id, seq);
+            while let Err(v) = QUEUE.try_push(item) {
+              item = v;
+              thread::yield_now();
+            }
+          }
+        });
+      }
+
+      let mut next = [0; PRODUCERS];
+      let mut received = 0;
+      while received < PRODUCERS * PER_PRODUCER {
+        if let Some((id, seq)) = QUEUE.pop() {
+          // producerごとの順序は保たれる
+          assert_eq!(seq, next[id]);
+          next[id] += 1;
+          received += 1;
+        } else {
+          thread::yield_now();
+        }
+      }
+    });
+    assert!(QUEUE.pop().is_none());
+  }
+
+  #[test]
+  fn full_and_drop() {
+    let q: SpinMpsc<String, 2> = SpinMpsc::new();
+    assert!(q.try_push("a".into()).is_ok());
+    assert!(q.try_push("b".into()).is_ok());
+    assert_eq!(q.try_push("c".into()), Err("c".to_string()));
+    assert_eq!(q.pop().as_deref(), Some("a"));
+    assert!(q.try_push("c".into()).is_ok());
+    assert_eq!(q.len(), 2);
+    // 残っている要素はdropされる
+  }
+}
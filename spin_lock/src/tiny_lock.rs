@@ -0,0 +1,155 @@
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// 0 = unlocked, 1..=MAX_READERS = readerの数, WRITE_LOCKED = writer
+const WRITE_LOCKED: u8 = u8::MAX;
+pub const TINY_MAX_READERS: u8 = 126;
+
+// 状態を1バイトに詰めたスピン式のRwLock。ロックの配列をstaticに置く用途向け
+// futexは使わず、取れるまでスピンする
+pub struct TinyLock<T> {
+  state: AtomicU8,
+  value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for TinyLock<T> where T: Send + Sync {}
+
+impl<T> TinyLock<T> {
+  pub const fn new(value: T) -> Self {
+    Self {
+      state: AtomicU8::new(0),
+      value: UnsafeCell::new(value),
+    }
+  }
+
+  // readerが上限に達していたらパニックせず、空くまでスピンする
+  pub fn read(&self) -> TinyReadGuard<'_, T> {
+    loop {
+      if let Some(guard) = self.try_read() {
+        return guard;
+      }
+      spin_loop();
+    }
+  }
+
+  pub fn try_read(&self) -> Option<TinyReadGuard<'_, T>> {
+    self.state
+      .fetch_update(Acquire, Relaxed, |s| (s < TINY_MAX_READERS).then(|| s + 1))
+      .ok()?;
+    Some(TinyReadGuard { lock: self })
+  }
+
+  pub fn write(&self) -> TinyWriteGuard<'_, T> {
+    loop {
+      if let Some(guard) = self.try_write() {
+        return guard;
+      }
+      spin_loop();
+    }
+  }
+
+  pub fn try_write(&self) -> Option<TinyWriteGuard<'_, T>> {
+    self.state.compare_exchange(0, WRITE_LOCKED, Acquire, Relaxed).ok()?;
+    Some(TinyWriteGuard { lock: self })
+  }
+}
+
+pub struct TinyReadGuard<'a, T> {
+  lock: &'a TinyLock<T>,
+}
+
+impl<T> Deref for TinyReadGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.value.get() }
+  }
+}
+
+impl<T> Drop for TinyReadGuard<'_, T> {
+  fn drop(&mut self) {
+    self.lock.state.fetch_sub(1, Release);
+  }
+}
+
+pub struct TinyWriteGuard<'a, T> {
+  lock: &'a TinyLock<T>,
+}
+
+impl<T> Deref for TinyWriteGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.value.get() }
+  }
+}
+
+impl<T> DerefMut for TinyWriteGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.value.get() }
+  }
+}
+
+impl<T> Drop for TinyWriteGuard<'_, T> {
+  fn drop(&mut self) {
+    self.lock.state.store(0, Release);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn readers_and_writers_exclude() {
+    assert_eq!(std::mem::size_of::<TinyLock<()>>(), 1);
+
+    let l = TinyLock::new(0);
+    let r = l.read();
+    assert!(l.try_write().is_none());
+    drop(r);
+    let w = l.write();
+    assert!(l.try_read().is_none());
+    drop(w);
+
+    let l = TinyLock::new((0, 0));
+    thread::scope(|s| {
+      for _ in 0..4 {
+        s.spawn(|| {
+          for _ in 0..100 {
+            let mut w = l.write();
+            w.0 += 1;
+            thread::yield_now();
+            w.1 += 1;
+            drop(w);
+            // writerが書きかけの値は見えない
+            let r = l.read();
+            assert_eq!(r.0, r.1);
+          }
+        });
+      }
+    });
+    assert_eq!(*l.read(), (400, 400));
+  }
+
+  #[test]
+  fn extra_reader_waits_for_free_slot() {
+    let l = TinyLock::new(());
+    let mut readers: Vec<_> = (0..TINY_MAX_READERS).map(|_| l.read()).collect();
+    assert!(l.try_read().is_none());
+    thread::scope(|s| {
+      let t = s.spawn(|| drop(l.read()));
+      thread::yield_now();
+      // 1つ空けば待っていたreaderが進める
+      readers.pop();
+      t.join().unwrap();
+    });
+    drop(readers);
+    assert!(l.try_write().is_some());
+  }
+}
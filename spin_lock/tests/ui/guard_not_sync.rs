@@ -0,0 +1,10 @@
+use std::cell::Cell;
+
+use spin_lock::Guard;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+  // Guardを共有すると&Tが他スレッドに渡るので、T: Syncが必要
+  assert_sync::<Guard<'static, Cell<i32>>>();
+}
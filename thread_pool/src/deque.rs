@@ -0,0 +1,231 @@
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{fence, AtomicIsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+
+use arc::Arc;
+
+// Chase-Levの固定長deque。持ち主のワーカーはbottom側でpush/popし、他のワーカーはtop側からstealする
+// 要素はtop..bottomの範囲にあり、bufferにはインデックスをcapで割った余りの位置に入る
+pub struct WorkStealingDeque<T> {
+	top: AtomicIsize,
+	bottom: AtomicIsize,
+	buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+unsafe impl<T: Send> Sync for WorkStealingDeque<T> {}
+unsafe impl<T: Send> Send for WorkStealingDeque<T> {}
+
+impl<T> WorkStealingDeque<T> {
+	// 持ち主が使うWorkerと、他のワーカーに配るStealerを返す
+	pub fn with_capacity(capacity: usize) -> (Worker<T>, Stealer<T>) {
+		assert!(capacity > 0, "deque capacity must be positive");
+		let deque = Arc::new(Self {
+			top: AtomicIsize::new(0),
+			bottom: AtomicIsize::new(0),
+			buffer: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+		});
+		(Worker { deque: deque.clone(), _not_sync: PhantomData }, Stealer { deque })
+	}
+
+	fn slot(&self, i: isize) -> *mut MaybeUninit<T> {
+		self.buffer[i as usize % self.buffer.len()].get()
+	}
+}
+
+impl<T> Drop for WorkStealingDeque<T> {
+	fn drop(&mut self) {
+		let (t, b) = (*self.top.get_mut(), *self.bottom.get_mut());
+		for i in t..b {
+			unsafe { (*self.slot(i)).assume_init_drop() };
+		}
+	}
+}
+
+// 持ち主だけが使う側。Syncではないので、push/popが同時に呼ばれることはない
+pub struct Worker<T> {
+	deque: Arc<WorkStealingDeque<T>>,
+	_not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Worker<T> {
+	// いっぱいなら値を返す
+	pub fn push(&self, value: T) -> Result<(), T> {
+		let d = &*self.deque;
+		let b = d.bottom.load(Relaxed);
+		let t = d.top.load(Acquire);
+		if b - t >= d.buffer.len() as isize {
+			return Err(value);
+		}
+		unsafe { (*d.slot(b)).write(value) };
+		// 書いた値がstealerに見えてからbottomを進める
+		fence(Release);
+		d.bottom.store(b + 1, Relaxed);
+		Ok(())
+	}
+
+	// 最後にpushしたものから取り出す
+	pub fn pop(&self) -> Option<T> {
+		let d = &*self.deque;
+		let b = d.bottom.load(Relaxed) - 1;
+		d.bottom.store(b, Relaxed);
+		// bottomを減らしたことと、stealerがtopを進めたことの、どちらかを必ず互いに観測する
+		fence(SeqCst);
+		let t = d.top.load(Relaxed);
+		if t > b {
+			// 空だった
+			d.bottom.store(b + 1, Relaxed);
+			return None;
+		}
+		let value = unsafe { d.slot(b).read() };
+		if t < b {
+			// 2つ以上残っていたので、stealerとは取り合わない
+			return Some(unsafe { value.assume_init() });
+		}
+		// 最後の1つはstealerとtopのCASで取り合う。負けたらvalueは相手のものなのでdropしない
+		let won = d.top.compare_exchange(t, t + 1, SeqCst, Relaxed).is_ok();
+		d.bottom.store(b + 1, Relaxed);
+		won.then(|| unsafe { value.assume_init() })
+	}
+
+	pub fn stealer(&self) -> Stealer<T> {
+		Stealer { deque: self.deque.clone() }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		let d = &*self.deque;
+		d.bottom.load(Relaxed) <= d.top.load(Relaxed)
+	}
+}
+
+// 他のワーカーが使う側。いくつcloneしてもよい
+pub struct Stealer<T> {
+	deque: Arc<WorkStealingDeque<T>>,
+}
+
+impl<T> Clone for Stealer<T> {
+	fn clone(&self) -> Self {
+		Self { deque: self.deque.clone() }
+	}
+}
+
+impl<T> Stealer<T> {
+	// 一番古いものを取り出す。他のstealerやpopとの取り合いに負けたらやり直し、空ならNone
+	pub fn steal(&self) -> Option<T> {
+		let d = &*self.deque;
+		loop {
+			let t = d.top.load(Acquire);
+			fence(SeqCst);
+			let b = d.bottom.load(Acquire);
+			if t >= b {
+				return None;
+			}
+			// CASに負けたときは、持ち主がこの位置に書き直している途中かもしれない。
+			// その場合はMaybeUninitのまま捨てるので、読んだ値は使わない
+			let value = unsafe { d.slot(t).read_volatile() };
+			if d.top.compare_exchange(t, t + 1, SeqCst, Relaxed).is_ok() {
+				return Some(unsafe { value.assume_init() });
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::AtomicUsize;
+	use std::thread;
+
+	use super::*;
+
+	#[test]
+	fn owner_lifo_stealer_fifo() {
+		let (w, s) = WorkStealingDeque::with_capacity(3);
+		for i in 0..3 {
+			w.push(i).unwrap();
+		}
+		assert_eq!(w.push(3), Err(3));
+		assert_eq!(s.steal(), Some(0));
+		assert_eq!(w.pop(), Some(2));
+		assert_eq!(w.pop(), Some(1));
+		assert_eq!(w.pop(), None);
+		assert_eq!(s.steal(), None);
+		assert!(w.is_empty());
+	}
+
+	#[test]
+	fn remaining_items_dropped_with_deque() {
+		static DROPS: AtomicUsize = AtomicUsize::new(0);
+		#[derive(Debug)]
+		struct DetectDrop(&'static str);
+		impl Drop for DetectDrop {
+			fn drop(&mut self) {
+				DROPS.fetch_add(1, Relaxed);
+			}
+		}
+
+		let (w, s) = WorkStealingDeque::with_capacity(2);
+		w.push(DetectDrop("a")).unwrap();
+		w.push(DetectDrop("b")).unwrap();
+		let stolen = s.steal().unwrap();
+		assert_eq!(stolen.0, "a");
+		// 両端がなくなったときに、残っていた"b"だけがdropされる
+		drop(w);
+		assert_eq!(DROPS.load(Relaxed), 0);
+		drop(s);
+		assert_eq!(DROPS.load(Relaxed), 1);
+		drop(stolen);
+		assert_eq!(DROPS.load(Relaxed), 2);
+	}
+
+	#[test]
+	fn idle_workers_steal_every_task_once() {
+		const WORKERS: usize = 4;
+		const TASKS: usize = 20000;
+		let executed: Vec<AtomicUsize> = (0..TASKS).map(|_| AtomicUsize::new(0)).collect();
+		let remaining = AtomicUsize::new(TASKS);
+
+		let (workers, stealers): (Vec<_>, Vec<_>) = (0..WORKERS).map(|_| WorkStealingDeque::with_capacity(64)).unzip();
+		let run = |task: usize| {
+			executed[task].fetch_add(1, Relaxed);
+			remaining.fetch_sub(1, Relaxed);
+		};
+
+		thread::scope(|s| {
+			for (id, worker) in workers.into_iter().enumerate() {
+				let stealers = &stealers;
+				let run = &run;
+				let remaining = &remaining;
+				s.spawn(move || {
+					// タスクは全部ワーカー0に積まれる。他のワーカーは盗むしかない
+					if id == 0 {
+						for task in 0..TASKS {
+							let mut task = task;
+							while let Err(t) = worker.push(task) {
+								task = t;
+								if let Some(t) = worker.pop() {
+									run(t);
+								}
+							}
+						}
+					}
+					while remaining.load(Relaxed) > 0 {
+						if let Some(t) = worker.pop() {
+							run(t);
+							continue;
+						}
+						let stolen = stealers.iter().enumerate()
+							.filter(|&(i, _)| i != id)
+							.find_map(|(_, s)| s.steal());
+						match stolen {
+							// 盗んだものは自分のdequeに積んでから実行する
+							Some(t) => worker.push(t).unwrap_or_else(run),
+							None => thread::yield_now(),
+						}
+					}
+				});
+			}
+		});
+		assert!(executed.iter().all(|n| n.load(Relaxed) == 1));
+	}
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+
+use arc::Arc;
+use condvar::Condvar;
+use mutex::Mutex;
+
+mod deque;
+
+pub use deque::{Stealer, WorkStealingDeque, Worker};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Queue {
+	jobs: VecDeque<Job>,
+	shutdown: bool,
+}
+
+// キューに積まれているジョブと実行中のジョブの数を数える
+struct WaitGroup {
+	count: Mutex<usize>,
+	zero: Condvar,
+}
+
+impl WaitGroup {
+	fn new() -> Self {
+		Self {
+			count: Mutex::new(0),
+			zero: Condvar::new(),
+		}
+	}
+
+	fn add(&self) {
+		*self.count.lock() += 1;
+	}
+
+	fn done(&self) {
+		let mut count = self.count.lock();
+		*count -= 1;
+		if *count == 0 {
+			self.zero.notify_all();
+		}
+	}
+
+	fn wait(&self) {
+		let mut count = self.count.lock();
+		while *count > 0 {
+			count = self.zero.wait(count);
+		}
+	}
+}
+
+struct Shared {
+	queue: Mutex<Queue>,
+	job_available: Condvar,
+	in_flight: WaitGroup,
+}
+
+pub struct ThreadPool {
+	shared: Arc<Shared>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+	pub fn new(size: usize) -> Self {
+		assert!(size > 0, "thread pool needs at least one worker");
+		let shared = Arc::new(Shared {
+			queue: Mutex::new(Queue { jobs: VecDeque::new(), shutdown: false }),
+			job_available: Condvar::new(),
+			in_flight: WaitGroup::new(),
+		});
+		let workers = (0..size)
+			.map(|_| {
+				let shared = shared.clone();
+				thread::spawn(move || worker(&shared))
+			})
+			.collect();
+		Self { shared, workers }
+	}
+
+	pub fn execute<F>(&self, f: F)
+	where
+		F: FnOnce() + Send + 'static,
+	{
+		// ジョブを積む前に数えておかないと、joinがすり抜けることがある
+		self.shared.in_flight.add();
+		self.shared.queue.lock().jobs.push_back(Box::new(f));
+		self.shared.job_available.notify_one();
+	}
+
+	// この時点までにexecuteされたジョブがすべて終わるまで待つ。プールは止めない
+	pub fn join(&self) {
+		self.shared.in_flight.wait();
+	}
+}
+
+fn worker(shared: &Shared) {
+	loop {
+		let mut queue = shared.queue.lock();
+		let job = loop {
+			if let Some(job) = queue.jobs.pop_front() {
+				break job;
+			}
+			if queue.shutdown {
+				return;
+			}
+			queue = shared.job_available.wait(queue);
+		};
+		drop(queue);
+
+		// ジョブがpanicしてもワーカーは止めずに次のジョブへ進む
+		// スレッドごと終わるとワーカーが減り、最後の1つが死ぬと残りのジョブが実行されずjoinが返らない
+		let _ = panic::catch_unwind(AssertUnwindSafe(job));
+		shared.in_flight.done();
+	}
+}
+
+impl Drop for ThreadPool {
+	fn drop(&mut self) {
+		// 残っているジョブを処理し終えてからワーカーを止める
+		self.shared.queue.lock().shutdown = true;
+		self.shared.job_available.notify_all();
+		for w in self.workers.drain(..) {
+			let _ = w.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::AtomicUsize;
+	use std::sync::atomic::Ordering::Relaxed;
+	use std::time::Duration;
+
+	use super::*;
+
+	#[test]
+	fn join_waits_for_in_flight_jobs() {
+		let pool = ThreadPool::new(4);
+		let completed = Arc::new(AtomicUsize::new(0));
+
+		for _ in 0..8 {
+			let completed = completed.clone();
+			pool.execute(move || {
+				thread::sleep(Duration::from_millis(50));
+				completed.fetch_add(1, Relaxed);
+			});
+		}
+		pool.join();
+		assert_eq!(completed.load(Relaxed), 8);
+
+		// join後もプールは使える
+		let c = completed.clone();
+		pool.execute(move || {
+			c.fetch_add(1, Relaxed);
+		});
+		pool.join();
+		assert_eq!(completed.load(Relaxed), 9);
+	}
+
+	#[test]
+	fn worker_survives_panicking_job() {
+		let pool = ThreadPool::new(1);
+		let ran = Arc::new(AtomicUsize::new(0));
+
+		pool.execute(|| panic!("job panicked"));
+		let r = ran.clone();
+		pool.execute(move || {
+			r.fetch_add(1, Relaxed);
+		});
+		pool.join();
+		assert_eq!(ran.load(Relaxed), 1);
+	}
+}
@@ -0,0 +1,38 @@
+// 各クレートのstress_*関数で使う、依存を増やさないための小さな乱数
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::thread;
+
+pub struct XorShift(u64);
+
+impl XorShift {
+  // スレッドごとに違う種で始める
+  pub fn new() -> Self {
+    Self::with_seed(RandomState::new().hash_one(thread::current().id()))
+  }
+
+  // 0だと同じ値しか出ないので、最下位ビットを立てる
+  pub fn with_seed(seed: u64) -> Self {
+    Self(seed | 1)
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+}
+
+impl Default for XorShift {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// 0〜3回yieldして、スレッドの進み方をずらす
+pub fn jitter(rng: &mut XorShift) {
+  for _ in 0..rng.next_u64() % 4 {
+    thread::yield_now();
+  }
+}